@@ -0,0 +1,321 @@
+use crate::config::{Mode, ProxyConfig, RecordHook, SequenceEnd, UpstreamClient};
+use crate::listener::ListenAddr;
+use crate::server::PactServer;
+use crate::tls::TlsCertificate;
+use pact_models::prelude::v4::V4Pact;
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use url::Url;
+
+/// Options of a `PactServer`, checked together by `build`
+#[derive(Default)]
+pub struct PactServerBuilder {
+    pacts_folder: Option<PathBuf>,
+    port: Option<u16>,
+    bind: Option<ListenAddr>,
+    pacts: Option<HashMap<(String, String), V4Pact>>,
+    ca_folder: Option<PathBuf>,
+    tls: Option<TlsCertificate>,
+    grpc_upstream: Option<Url>,
+    descriptor_set: Option<PathBuf>,
+    rewrite_upstream_urls: bool,
+    config: ProxyConfig,
+}
+
+impl PactServerBuilder {
+    /// Folder the pacts are read from and recorded to, required
+    pub fn pacts_folder(mut self, pacts_folder: impl Into<PathBuf>) -> Self {
+        self.pacts_folder = Some(pacts_folder.into());
+        self
+    }
+
+    /// Port on localhost, the OS assigns a free one when neither the port nor `bind` is set
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Address to listen on instead of localhost, e.g. `0.0.0.0:8080` or a unix socket
+    pub fn bind(mut self, addr: ListenAddr) -> Self {
+        self.bind = Some(addr);
+        self
+    }
+
+    /// Pacts served instead of the ones in the folder
+    pub fn pacts(mut self, pacts: HashMap<(String, String), V4Pact>) -> Self {
+        self.pacts = Some(pacts);
+        self
+    }
+
+    /// What the server does with requests that have no recorded interaction, `Record` by default
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+
+    /// Name of the consumer in the recorded pacts, `consumer` by default
+    pub fn consumer_name(mut self, consumer: &str) -> Self {
+        self.config.consumer = Some(consumer.to_string());
+        self
+    }
+
+    /// Records the values of these request and response headers as `<redacted>`
+    pub fn redact_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config
+            .redacted_headers
+            .extend(headers.into_iter().map(Into::into));
+        self
+    }
+
+    /// See `PactServer::replay_sequences`
+    pub fn sequence(mut self, end: SequenceEnd) -> Self {
+        self.config.sequence = Some(end);
+        self
+    }
+
+    /// See `PactServer::set_provider_state`
+    pub fn provider_state(self, provider_state: &str) -> Self {
+        *self.config.provider_state.lock().unwrap() = Some(provider_state.to_string());
+        self
+    }
+
+    /// Attempts, timeout and certificate checks of the requests sent upstream
+    pub fn upstream_client(mut self, upstream_client: UpstreamClient) -> Self {
+        self.config.upstream_client = upstream_client;
+        self
+    }
+
+    /// Calls the hook with every new interaction before it is recorded, after the redaction
+    pub fn on_record<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut HttpRequest, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.config.record_hooks.push(RecordHook(Arc::new(hook)));
+        self
+    }
+
+    /// Intercepts https sent through the proxy with CONNECT, see
+    /// `PactServer::with_https_interception`
+    pub fn intercept_https(mut self, ca_folder: impl Into<PathBuf>) -> Self {
+        self.ca_folder = Some(ca_folder.into());
+        self
+    }
+
+    /// Serves https instead of http, see `PactServer::serve_tls`
+    pub fn tls(mut self, certificate: TlsCertificate) -> Self {
+        self.tls = Some(certificate);
+        self
+    }
+
+    /// Serves grpc recorded from the upstream, see `PactServer::with_grpc_server`
+    pub fn grpc(mut self, upstream: Url, descriptor_set: Option<PathBuf>) -> Self {
+        self.grpc_upstream = Some(upstream);
+        self.descriptor_set = descriptor_set;
+        self
+    }
+
+    /// See `PactServer::rewrite_upstream_urls`
+    pub fn rewrite_upstream_urls(mut self) -> Self {
+        self.rewrite_upstream_urls = true;
+        self
+    }
+
+    /// See `PactServer::record_redirects`
+    pub fn record_redirects(mut self) -> Self {
+        self.config.record_redirects = true;
+        self
+    }
+
+    /// See `PactServer::keep_exact_bodies`
+    pub fn keep_exact_bodies(mut self) -> Self {
+        self.config.exact_bodies = true;
+        self
+    }
+
+    /// See `PactServer::add_via_header`
+    pub fn via(mut self, pseudonym: &str) -> Self {
+        self.config.via = Some(pseudonym.to_string());
+        self
+    }
+
+    /// See `PactServer::add_forwarded_headers`
+    pub fn forwarded_headers(mut self) -> Self {
+        self.config.forwarded_headers = true;
+        self
+    }
+
+    /// See `PactServer::spill_bodies_over`
+    pub fn spill_bodies_over(mut self, bytes: usize) -> Self {
+        self.config.spill_bodies_over = Some(bytes);
+        self
+    }
+
+    /// See `PactServer::speed_up_streams`
+    pub fn speed_up_streams(mut self, factor: f64) -> Self {
+        self.config.stream_speed_up = Some(factor);
+        self
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.port.is_some() && self.bind.is_some() {
+            return Err("Either the port or the bind address can be set".into());
+        }
+        if self.grpc_upstream.is_some() {
+            if self.bind.is_some() || self.ca_folder.is_some() || self.tls.is_some() {
+                return Err("The grpc server listens on a localhost port without tls".into());
+            }
+            if self.rewrite_upstream_urls {
+                return Err("Urls are not rewritten in grpc messages".into());
+            }
+        }
+        if self.config.consumer.as_deref() == Some("") {
+            return Err("The consumer name is empty".into());
+        }
+        if let Some(factor) = self.config.stream_speed_up {
+            if !(factor >= 0.0 && factor.is_finite()) {
+                return Err(format!("Invalid stream speed up {factor}").into());
+            }
+        }
+        if self.config.upstream_client.attempts == 0 {
+            return Err("The upstream client needs at least one attempt".into());
+        }
+        if let Some(ListenAddr::Unix(_)) = self.bind {
+            if self.rewrite_upstream_urls {
+                return Err("Urls can't be rewritten to a unix socket".into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<PactServer, Box<dyn Error>> {
+        self.validate()?;
+        let pacts_folder = self.pacts_folder.ok_or("The pacts folder is required")?;
+        let port = self.port.unwrap_or(0).to_string();
+        let mut server = match self.grpc_upstream {
+            Some(upstream) => PactServer::with_grpc_server(
+                &pacts_folder,
+                self.pacts,
+                Some(port),
+                upstream,
+                self.descriptor_set.as_deref(),
+            )?,
+            None => {
+                let addr = self.bind.unwrap_or_else(|| ListenAddr::localhost(&port));
+                PactServer::listen(&pacts_folder, self.pacts, &addr, self.ca_folder.as_deref())?
+            }
+        };
+        server.config = self.config;
+        if let Some(certificate) = &self.tls {
+            server.serve_tls(certificate)?;
+        }
+        if self.rewrite_upstream_urls {
+            server.rewrite_upstream_urls()?;
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pact::headers::REDACTED;
+    use crate::test_utils::test_pacts_folder;
+    use crate::test_utils::TestUpstream;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_build_validation() {
+        assert!(PactServer::builder().build().is_err());
+        let pacts_folder = test_pacts_folder();
+        let invalid = [
+            PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .port(8080)
+                .bind(ListenAddr::localhost("8080")),
+            PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .grpc(Url::parse("http://localhost:50051").unwrap(), None)
+                .intercept_https(&pacts_folder),
+            PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .consumer_name(""),
+            PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .speed_up_streams(-1.0),
+        ];
+        for builder in invalid {
+            assert!(builder.build().is_err());
+        }
+        let server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .consumer_name("web-app")
+            .build()
+            .unwrap();
+        assert_ne!(server.port().unwrap(), "0");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_builder() {
+        let upstream = TestUpstream::start(|_| {
+            Response::from_string("built")
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Set-Cookie"[..], &b"session=1"[..]).unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .consumer_name("web-app")
+            .redact_headers(["authorization", "Set-Cookie"])
+            .on_record(|_, response| {
+                response
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .insert("x-recorded".to_string(), vec!["true".to_string()]);
+            })
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://localhost:{port}/http/127.0.0.1:{}", upstream.port);
+        let response = reqwest::Client::new()
+            .get(url.clone() + "/recorded")
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        // only the recording is redacted and edited by the hooks
+        assert_eq!(response.headers()["set-cookie"], "session=1");
+        assert!(response.headers().get("x-recorded").is_none());
+        assert_eq!(response.text().await.unwrap(), "built");
+        pact_server.stop().unwrap();
+        let pact = std::fs::read_to_string(pacts_folder.join("web-app-127.0.0.1.json")).unwrap();
+        assert!(!pact.contains("secret") && !pact.contains("session=1"));
+        assert!(pact.contains(REDACTED) && pact.contains("x-recorded"));
+
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .port(0)
+            .consumer_name("web-app")
+            .mode(Mode::Replay)
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://localhost:{port}/http/127.0.0.1:{}", upstream.port);
+        let response = reqwest::get(url.clone() + "/recorded").await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "built");
+        let response = reqwest::get(url + "/not-recorded").await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+    }
+}
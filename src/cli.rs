@@ -1,48 +1,217 @@
-use clap::{arg, command, value_parser};
+use crate::config::SequenceEnd;
+use crate::listener::ListenAddr;
+use crate::routing::{Route, SchemeMap};
+use crate::tls::TlsCertificate;
+use clap::{arg, command, value_parser, ArgAction};
 use std::error::Error;
-use std::net::TcpListener;
 use std::path::PathBuf;
+use std::str::FromStr;
+use url::Url;
 
-fn unwrap_commandline_args(
-    matches: &clap::ArgMatches,
-) -> Result<(PathBuf, String), Box<dyn Error>> {
-    let pact_file = matches
+pub struct CommandlineArgs {
+    pub pact_files_folder: PathBuf,
+    pub port: Option<u16>,
+    pub bind: Option<ListenAddr>,
+    pub port_file: Option<PathBuf>,
+    pub ca_folder: Option<PathBuf>,
+    pub routes: Vec<Route>,
+    pub host_routing_port: Option<String>,
+    pub schemes: SchemeMap,
+    pub rewrite_urls: bool,
+    pub record_redirects: bool,
+    pub exact_bodies: bool,
+    pub via: Option<String>,
+    pub forwarded_headers: bool,
+    pub spill_bodies_over: Option<usize>,
+    pub speed_up_streams: Option<f64>,
+    pub grpc_upstream: Option<Url>,
+    pub descriptor_set: Option<PathBuf>,
+    pub tls: Option<TlsCertificate>,
+    pub sequence: Option<SequenceEnd>,
+}
+
+fn unwrap_commandline_args(matches: &clap::ArgMatches) -> Result<CommandlineArgs, Box<dyn Error>> {
+    let pact_files_folder = matches
         .get_one::<PathBuf>("pact_files_folder")
         .ok_or("Can't open pact_file")?
         .to_path_buf();
-    let port = parse_port(matches)?;
-    Ok((pact_file, port))
+    let port = matches.get_one::<u16>("port").copied();
+    let bind = matches
+        .get_one::<String>("bind")
+        .map(|addr| ListenAddr::from_str(addr))
+        .transpose()?;
+    let port_file = matches.get_one::<PathBuf>("port_file").cloned();
+    let ca_folder = matches.get_one::<PathBuf>("ca_folder").cloned();
+    let routes = parse_routes(matches)?;
+    let host_routing_port = matches.get_one::<String>("host_routing_port").cloned();
+    let schemes = parse_schemes(matches)?;
+    let rewrite_urls = matches.get_flag("rewrite_urls");
+    let record_redirects = matches.get_flag("record_redirects");
+    let exact_bodies = matches.get_flag("exact_bodies");
+    let via = matches.get_one::<String>("via").cloned();
+    let forwarded_headers = matches.get_flag("forwarded_headers");
+    let spill_bodies_over = matches.get_one::<usize>("spill_bodies_over").copied();
+    let speed_up_streams = matches.get_one::<f64>("speed_up_streams").copied();
+    let grpc_upstream = matches
+        .get_one::<String>("grpc_upstream")
+        .map(|url| Url::parse(url))
+        .transpose()?;
+    let descriptor_set = matches.get_one::<PathBuf>("descriptor_set").cloned();
+    let tls = parse_tls(matches)?;
+    let sequence = matches
+        .get_one::<String>("sequence")
+        .map(|end| SequenceEnd::from_str(end))
+        .transpose()?;
+    Ok(CommandlineArgs {
+        pact_files_folder,
+        port,
+        bind,
+        port_file,
+        ca_folder,
+        routes,
+        host_routing_port,
+        schemes,
+        rewrite_urls,
+        record_redirects,
+        exact_bodies,
+        via,
+        forwarded_headers,
+        spill_bodies_over,
+        speed_up_streams,
+        grpc_upstream,
+        descriptor_set,
+        tls,
+        sequence,
+    })
+}
+
+fn parse_tls(matches: &clap::ArgMatches) -> Result<Option<TlsCertificate>, Box<dyn Error>> {
+    let cert = matches.get_one::<PathBuf>("tls_cert").cloned();
+    let key = matches.get_one::<PathBuf>("tls_key").cloned();
+    let self_signed = matches.get_one::<PathBuf>("tls_self_signed").cloned();
+    match (cert, key, self_signed) {
+        (None, None, None) => Ok(None),
+        (Some(cert), Some(key), None) => Ok(Some(TlsCertificate::Files { cert, key })),
+        (None, None, Some(folder)) => Ok(Some(TlsCertificate::SelfSigned(folder))),
+        _ => Err("Either --tls_cert with --tls_key or --tls_self_signed is expected".into()),
+    }
 }
 
-fn parse_port(matches: &clap::ArgMatches) -> Result<String, Box<dyn Error>> {
-    let default_port = get_rand_port().to_string();
-    let port = matches
-        .get_one::<String>("port")
-        .unwrap_or(&default_port)
-        .to_string();
-    Ok(port)
+fn parse_schemes(matches: &clap::ArgMatches) -> Result<SchemeMap, Box<dyn Error>> {
+    let mut schemes = SchemeMap::default();
+    if let Some(values) = matches.get_many::<String>("scheme") {
+        for value in values {
+            schemes.insert_entry(value)?;
+        }
+    }
+    Ok(schemes)
 }
 
-pub fn get_commandline_args() -> Result<(PathBuf, String), Box<dyn Error>> {
+fn parse_routes(matches: &clap::ArgMatches) -> Result<Vec<Route>, Box<dyn Error>> {
+    let mut routes = vec![];
+    if let Some(values) = matches.get_many::<String>("route") {
+        for value in values {
+            routes.push(Route::from_str(value)?);
+        }
+    }
+    Ok(routes)
+}
+
+pub fn get_commandline_args() -> Result<CommandlineArgs, Box<dyn Error>> {
     let matches = command!()
         .arg(
             arg!(-f --pact_files_folder <FILE> "The pact file to load")
                 .required(true)
                 .value_parser(value_parser!(PathBuf)),
         )
-        .arg(arg!(-p --port <PORT> "The port to run the mock service on").required(false))
+        .arg(
+            arg!(-p --port <PORT> "The port to run the mock service on")
+                .required(false)
+                .value_parser(value_parser!(u16)),
+        )
+        .arg(
+            arg!(-b --bind <ADDR> "Address to listen on instead of localhost:<PORT>, e.g. 0.0.0.0:8080, [::]:8080 or unix:/tmp/pact-proxy.sock, the routes listen on the same host")
+                .required(false),
+        )
+        .arg(
+            arg!(--port_file <FILE> "Write the port, or the unix socket address, to this file once the proxy is ready")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-c --ca_folder <DIR> "Intercept https, the generated CA certificate is written to this folder")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-r --route <ROUTE> "Listener port proxying plain paths to an upstream, e.g. 9001=https://api.binance.com")
+                .required(false)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--host_routing_port <PORT> "Listener port taking the upstream host from the Host header")
+                .required(false),
+        )
+        .arg(
+            arg!(-s --scheme <SCHEME> "Scheme of an upstream host for host header routing, e.g. api.binance.com=https or *=https")
+                .required(false)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--rewrite_urls "Rewrite the absolute upstream urls in responses into urls of the proxy")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--record_redirects "Return the upstream redirects to the clients and record every hop")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--exact_bodies "Record and replay the exact bytes of the bodies instead of normalized json")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(arg!(--via <NAME> "Pseudonym of the proxy added to the Via headers").required(false))
+        .arg(
+            arg!(--forwarded_headers "Add the X-Forwarded-For, X-Forwarded-Host and X-Forwarded-Proto headers to the requests")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--spill_bodies_over <BYTES> "Store the bodies larger than this in files next to the pacts")
+                .required(false)
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--speed_up_streams <FACTOR> "Replay the streaming responses this many times faster than recorded, 0 sends them at once")
+                .required(false)
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--grpc_upstream <URL> "Serve grpc over http/2 and record the unary calls to this upstream, e.g. http://localhost:50051")
+                .required(false),
+        )
+        .arg(
+            arg!(--descriptor_set <FILE> "Descriptor set of the grpc services, their messages are recorded as json")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--tls_cert <FILE> "Serve https with this pem certificate chain")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--tls_key <FILE> "Pem private key of the --tls_cert certificate")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--tls_self_signed <DIR> "Serve https with a self-signed localhost certificate generated into this folder")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--sequence <END> "Replay the repeated calls in the order they were recorded, then repeat_last, cycle or error")
+                .required(false),
+        )
         .get_matches();
     unwrap_commandline_args(&matches)
 }
-
-pub fn get_rand_port() -> u16 {
-    let mut port = rand::random::<u16>() % 1000 + 10000;
-    while !port_is_available(port) {
-        port = rand::random::<u16>() % 1000 + 10000
-    }
-    port
-}
-
-fn port_is_available(port: u16) -> bool {
-    TcpListener::bind(("127.0.0.1", port)).is_ok()
-}
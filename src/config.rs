@@ -0,0 +1,291 @@
+use crate::pact::edit::CONSUMER_NAME;
+use crate::pact::headers::redact_headers;
+use crate::pact::rewrite::UrlRewriter;
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Options shared by the threads serving the requests
+#[derive(Default, Clone, Debug)]
+pub struct ProxyConfig {
+    /// Rewrites the upstream urls in the responses sent to the clients
+    pub url_rewriter: Option<UrlRewriter>,
+    /// Returns the redirects to the clients instead of following them,
+    /// so that every hop is recorded as its own interaction
+    pub record_redirects: bool,
+    /// Records the bodies byte for byte instead of normalizing the json
+    pub exact_bodies: bool,
+    /// Pseudonym of the proxy added to the `Via` headers
+    pub via: Option<String>,
+    /// Adds the `X-Forwarded-*` headers to the requests sent upstream
+    pub forwarded_headers: bool,
+    /// Size in bytes above which the bodies are stored in side files
+    pub spill_bodies_over: Option<usize>,
+    /// Divides the delays between the chunks of the replayed streams, 0 sends them at once
+    pub stream_speed_up: Option<f64>,
+    /// Requests being served, shared by every copy of the config
+    pub in_flight: Arc<InFlight>,
+    /// Connections the front passes through to the http server
+    pub passed_connections: Arc<PassedConnections>,
+    pub mode: Mode,
+    /// Name of the consumer in the pacts, `consumer` when not set
+    pub consumer: Option<String>,
+    /// Headers of the requests and responses whose values are not recorded
+    pub redacted_headers: Vec<String>,
+    pub upstream_client: UpstreamClient,
+    /// Called with every new interaction before it is recorded
+    pub record_hooks: Vec<RecordHook>,
+    /// Provider state of the requests without the provider state header, shared by every
+    /// copy of the config so that it can be changed while the server runs
+    pub provider_state: Arc<Mutex<Option<String>>>,
+    /// Records the repeated calls of an interaction and replays them in order, e.g. the
+    /// answers to polling, the first recording answers every call when not set
+    pub sequence: Option<SequenceEnd>,
+}
+
+impl ProxyConfig {
+    pub fn consumer(&self) -> &str {
+        self.consumer.as_deref().unwrap_or(CONSUMER_NAME)
+    }
+
+    pub fn provider_state(&self) -> Option<String> {
+        self.provider_state.lock().unwrap().clone()
+    }
+
+    /// How the lookup of a sequence goes on past its last recording. On record the
+    /// later calls find no interaction so that they are recorded
+    pub fn sequence_end(&self) -> Option<SequenceEnd> {
+        self.sequence.map(|end| match self.mode {
+            Mode::Record => SequenceEnd::Error,
+            Mode::Replay => end,
+        })
+    }
+
+    /// Redacts the headers and runs the hooks on an interaction about to be recorded
+    pub fn before_recording(&self, request: &mut HttpRequest, response: &mut HttpResponse) {
+        for headers in [request.headers.as_mut(), response.headers.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            redact_headers(headers, &self.redacted_headers);
+        }
+        for hook in &self.record_hooks {
+            (hook.0)(request, response);
+        }
+    }
+}
+
+/// What the proxy does with requests that have no recorded interaction
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Forwards them upstream and records the responses
+    #[default]
+    Record,
+    /// Answers them with 404 without reaching the upstream, e.g. in CI
+    Replay,
+}
+
+/// What the calls of a sequence get once they went past its last recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceEnd {
+    /// The last recording answers the later calls
+    RepeatLast,
+    /// The sequence starts over from the first recording
+    Cycle,
+    /// The later calls find no interaction, they get a 404 on replay
+    Error,
+}
+
+impl FromStr for SequenceEnd {
+    type Err = Box<dyn Error>;
+
+    fn from_str(end: &str) -> Result<Self, Self::Err> {
+        match end {
+            "repeat_last" => Ok(SequenceEnd::RepeatLast),
+            "cycle" => Ok(SequenceEnd::Cycle),
+            "error" => Ok(SequenceEnd::Error),
+            _ => Err(format!("{end} is not one of repeat_last, cycle, error").into()),
+        }
+    }
+}
+
+/// Settings of the client sending the requests upstream
+#[derive(Clone, Debug)]
+pub struct UpstreamClient {
+    /// Attempts for an idempotent request until the upstream answers with a success,
+    /// the other requests are only sent again when they could not connect
+    pub attempts: usize,
+    /// Wait between the attempts
+    pub retry_delay: Duration,
+    /// Timeout of a whole attempt, from connecting to the end of the body
+    pub timeout: Option<Duration>,
+    /// Accepts invalid upstream certificates, e.g. self-signed ones of a test environment
+    pub accept_invalid_certs: bool,
+}
+
+impl Default for UpstreamClient {
+    fn default() -> Self {
+        UpstreamClient {
+            attempts: 5,
+            retry_delay: Duration::from_secs(1),
+            timeout: None,
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+type RecordFn = dyn Fn(&mut HttpRequest, &mut HttpResponse) + Send + Sync;
+
+/// Callback that can change the request and the response of an interaction before it is recorded
+#[derive(Clone)]
+pub struct RecordHook(pub Arc<RecordFn>);
+
+impl fmt::Debug for RecordHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RecordHook")
+    }
+}
+
+/// Counts the requests being served, so that a shutdown can wait for them
+#[derive(Default, Debug)]
+pub struct InFlight(AtomicUsize);
+
+impl InFlight {
+    /// Counts the request until the returned guard is dropped
+    pub fn enter(self: &Arc<Self>) -> InFlightRequest {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest(self.clone())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightRequest(Arc<InFlight>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Connections the front passes through to the http server, by their local address
+#[derive(Default, Debug)]
+pub struct PassedConnections(Mutex<HashMap<SocketAddr, PassedConnection>>);
+
+#[derive(Debug)]
+struct PassedConnection {
+    client: PassedClient,
+    /// Requests forwarded to the http server that it did not hand over to be served yet
+    queued: Vec<InFlightRequest>,
+}
+
+/// Client of a connection passed through by the front
+#[derive(Clone, Copy, Debug)]
+pub struct PassedClient {
+    /// `None` for the clients of a unix socket
+    pub addr: Option<SocketAddr>,
+    /// `https` when the front terminated tls
+    pub proto: &'static str,
+}
+
+impl PassedConnections {
+    /// Registers the connection until the returned guard is dropped
+    pub fn register(
+        self: &Arc<Self>,
+        relay_addr: SocketAddr,
+        client: PassedClient,
+    ) -> RegisteredConnection {
+        let connection = PassedConnection {
+            client,
+            queued: vec![],
+        };
+        self.0.lock().unwrap().insert(relay_addr, connection);
+        RegisteredConnection(self.clone(), relay_addr)
+    }
+
+    /// The client of a request the http server received from `remote_addr`, which is
+    /// the client itself when the connection was not passed through by the front
+    pub fn client(&self, remote_addr: Option<SocketAddr>) -> PassedClient {
+        let connections = self.0.lock().unwrap();
+        let passed = remote_addr.and_then(|addr| connections.get(&addr));
+        match passed {
+            Some(connection) => connection.client,
+            None => PassedClient {
+                addr: remote_addr,
+                proto: "http",
+            },
+        }
+    }
+
+    /// Takes over the count of a request forwarded from `remote_addr`, which the http
+    /// server hands over to be served
+    pub fn received(&self, remote_addr: Option<SocketAddr>) -> Option<InFlightRequest> {
+        let mut connections = self.0.lock().unwrap();
+        connections.get_mut(&remote_addr?)?.queued.pop()
+    }
+}
+
+/// Connection passed through by the front, unregistered when dropped
+pub struct RegisteredConnection(Arc<PassedConnections>, SocketAddr);
+
+impl RegisteredConnection {
+    /// Counts a forwarded request as in flight while it waits in the queue of the http server
+    pub fn forwarded(&self, request: InFlightRequest) {
+        if let Some(connection) = self.0 .0.lock().unwrap().get_mut(&self.1) {
+            connection.queued.push(request);
+        }
+    }
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        // the requests the http server did not receive are not in flight anymore
+        self.0 .0.lock().unwrap().remove(&self.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_requests_are_in_flight_until_received() {
+        let config = ProxyConfig::default();
+        let relay_addr = "127.0.0.1:40000".parse().unwrap();
+        let client = PassedClient {
+            addr: Some("[::1]:50000".parse().unwrap()),
+            proto: "https",
+        };
+        let connection = config.passed_connections.register(relay_addr, client);
+        for _ in 0..2 {
+            connection.forwarded(config.in_flight.enter());
+        }
+        assert!(!config.in_flight.is_idle());
+        let received = config.passed_connections.received(Some(relay_addr));
+        assert!(received.is_some());
+        assert_eq!(
+            config.passed_connections.client(Some(relay_addr)).addr,
+            client.addr
+        );
+        drop(received);
+        // still queued
+        assert!(!config.in_flight.is_idle());
+        drop(connection);
+        assert!(config.in_flight.is_idle());
+        let other_addr = Some("127.0.0.1:40001".parse().unwrap());
+        assert!(config.passed_connections.received(other_addr).is_none());
+        assert_eq!(
+            config.passed_connections.client(other_addr).addr,
+            other_addr
+        );
+    }
+}
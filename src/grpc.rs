@@ -0,0 +1,630 @@
+use crate::config::{Mode, ProxyConfig};
+use crate::pact::edit;
+use crate::pact::headers::is_hop_by_hop;
+use crate::pacts::Pacts;
+use bytes::{BufMut, Bytes, BytesMut};
+#[cfg(feature = "flame_it")]
+use flamer::flame;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Request, Response};
+use hyper_rustls::HttpsConnector;
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::{ContentType, ContentTypeHint};
+use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tracing::debug;
+use url::Url;
+
+const TRAILERS: &str = "trailers";
+/// grpc status of the error responses of the proxy itself
+const UNAVAILABLE: &str = "14";
+
+/// Services of a descriptor set, the messages of their methods are recorded as json
+#[derive(Clone, Debug)]
+pub struct Descriptors {
+    pool: DescriptorPool,
+}
+
+impl Descriptors {
+    /// Reads a `FileDescriptorSet`, e.g. written by `protoc --descriptor_set_out`
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    pub fn decode(descriptor_set: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(Descriptors {
+            pool: DescriptorPool::decode(descriptor_set)?,
+        })
+    }
+
+    /// Method of a `/<package>.<service>/<method>` path
+    fn method(&self, path: &str) -> Option<MethodDescriptor> {
+        let (service, method) = path.trim_start_matches('/').split_once('/')?;
+        self.pool
+            .get_service_by_name(service)?
+            .methods()
+            .find(|method_descriptor| method_descriptor.name() == method)
+    }
+}
+
+type UpstreamClient = Client<HttpsConnector<HttpConnector>>;
+
+/// HTTP/2 listener recording the unary calls to a grpc upstream and replaying them
+pub struct GrpcServer {
+    listener: Mutex<Option<TcpListener>>,
+    local_addr: SocketAddr,
+    upstream: Url,
+    descriptors: Option<Descriptors>,
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    shutdown_receiver: Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+struct CallContext {
+    server: Arc<GrpcServer>,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+    client: UpstreamClient,
+}
+
+impl GrpcServer {
+    pub fn bind(
+        addr: &str,
+        upstream: Url,
+        descriptors: Option<Descriptors>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        Ok(GrpcServer {
+            local_addr: listener.local_addr()?,
+            listener: Mutex::new(Some(listener)),
+            upstream,
+            descriptors,
+            shutdown: Mutex::new(Some(shutdown)),
+            shutdown_receiver: Mutex::new(Some(shutdown_receiver)),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Serves the calls until `stop` is called
+    pub async fn serve(
+        self: Arc<Self>,
+        pacts: Arc<Pacts>,
+        config: Arc<ProxyConfig>,
+    ) -> Result<(), Box<dyn Error>> {
+        let listener = self
+            .listener
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or("grpc server is already started")?;
+        let shutdown_receiver = self.shutdown_receiver.lock().unwrap().take().unwrap();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http2()
+            .build();
+        let context = Arc::new(CallContext {
+            server: self.clone(),
+            pacts,
+            config,
+            client: Client::builder().http2_only(true).build(connector),
+        });
+        let make_service = make_service_fn(move |_| {
+            let context = context.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle_call(request, context.clone())
+                }))
+            }
+        });
+        debug!("Serving grpc on {}", self.local_addr);
+        let server = tokio::spawn(
+            hyper::Server::from_tcp(listener)?
+                .http2_only(true)
+                .serve(make_service),
+        );
+        // clients keep their http/2 connections open, a graceful shutdown would wait for them
+        shutdown_receiver.await.ok();
+        server.abort();
+        match server.await {
+            Ok(result) => Ok(result?),
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn stop(&self) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn handle_call(
+    request: Request<Body>,
+    context: Arc<CallContext>,
+) -> Result<Response<Body>, Infallible> {
+    let _in_flight = context.config.in_flight.enter();
+    match get_call_response(request, &context).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            debug!("Error: {}", e);
+            // trailers only response
+            let grpc_message = e.to_string().replace(['\r', '\n'], " ");
+            Ok(Response::builder()
+                .header("content-type", "application/grpc")
+                .header("grpc-status", UNAVAILABLE)
+                .header("grpc-message", grpc_message)
+                .body(Body::empty())
+                .unwrap())
+        }
+    }
+}
+
+#[cfg_attr(feature = "flame_it", flame)]
+async fn get_call_response(
+    request: Request<Body>,
+    context: &CallContext,
+) -> Result<Response<Body>, Box<dyn Error>> {
+    let server = &context.server;
+    let path = request.uri().path().to_string();
+    let method = server
+        .descriptors
+        .as_ref()
+        .and_then(|descriptors| descriptors.method(&path));
+    let headers = grpc_headers(request.headers());
+    let message = hyper::body::to_bytes(request.into_body()).await?;
+    let pact_request = HttpRequest {
+        method: "POST".to_string(),
+        path: server.upstream.join(&path)?.to_string(),
+        headers: (!headers.is_empty()).then_some(headers),
+        body: message_to_body(method.as_ref().map(|method| method.input()), &message),
+        ..Default::default()
+    };
+    let description =
+        path.trim_start_matches('/').to_owned() + " " + &request_key(&pact_request.body);
+    let consumer_provider = edit::get_consumer_provider(&pact_request, context.config.consumer())?;
+    let (consumer, provider) = &consumer_provider;
+    let provider_state = context.config.provider_state();
+    let (pact_response, metadata) = match context.pacts.get_pact_response(
+        consumer,
+        provider,
+        &description,
+        provider_state.as_deref(),
+        context.config.sequence_end(),
+    ) {
+        Some(recorded_response) => {
+            debug!("Match found for {}", description);
+            (recorded_response.response, recorded_response.metadata)
+        }
+        None if context.config.mode == Mode::Replay => {
+            return Err(format!("No interaction recorded for {description}").into());
+        }
+        None => {
+            let (mut pact_response, metadata) =
+                forward_call(context, &pact_request, message, method.as_ref()).await?;
+            let mut recorded_request = pact_request.clone();
+            context
+                .config
+                .before_recording(&mut recorded_request, &mut pact_response);
+            context.pacts.add_interaction(
+                &consumer_provider,
+                &description,
+                provider_state.as_deref(),
+                &recorded_request,
+                &pact_response,
+                metadata.clone(),
+            )?;
+            context.pacts.save_pact(consumer, provider)?;
+            (pact_response, metadata)
+        }
+    };
+    let message = body_to_message(
+        method.as_ref().map(|method| method.output()),
+        &pact_response.body,
+    )?;
+    call_response(&pact_response, message, trailers(&metadata)?)
+}
+
+async fn forward_call(
+    context: &CallContext,
+    pact_request: &HttpRequest,
+    message: Bytes,
+    method: Option<&MethodDescriptor>,
+) -> Result<(HttpResponse, HashMap<String, Value>), Box<dyn Error>> {
+    let mut upstream_request = Request::post(pact_request.path.as_str());
+    for (key, values) in pact_request.headers.iter().flatten() {
+        for value in values {
+            upstream_request = upstream_request.header(key.as_str(), value.as_str());
+        }
+    }
+    let upstream_request = upstream_request
+        .header("te", "trailers")
+        .body(Body::from(message))?;
+    let response = context.client.request(upstream_request).await?;
+    let (parts, mut body) = response.into_parts();
+    let mut message = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        message.extend_from_slice(&chunk?);
+    }
+    let message = message.freeze();
+    let headers = grpc_headers(&parts.headers);
+    let pact_response = HttpResponse {
+        status: parts.status.as_u16(),
+        headers: (!headers.is_empty()).then_some(headers),
+        body: message_to_body(method.map(|method| method.output()), &message),
+        ..Default::default()
+    };
+    let mut metadata = HashMap::new();
+    if let Some(trailers) = body.trailers().await? {
+        metadata.insert(
+            TRAILERS.to_string(),
+            serde_json::to_value(grpc_headers(&trailers))?,
+        );
+    }
+    Ok((pact_response, metadata))
+}
+
+fn call_response(
+    pact_response: &HttpResponse,
+    message: Bytes,
+    trailers: Option<HeaderMap>,
+) -> Result<Response<Body>, Box<dyn Error>> {
+    let mut response = Response::builder().status(pact_response.status);
+    for (key, values) in pact_response.headers.iter().flatten() {
+        for value in values {
+            response = response.header(key.as_str(), value.as_str());
+        }
+    }
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if !message.is_empty() && sender.send_data(message).await.is_err() {
+            return;
+        }
+        if let Some(trailers) = trailers {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    Ok(response.body(body)?)
+}
+
+/// Metadata of a call without the headers of the connection
+fn grpc_headers(headers: &HeaderMap) -> HashMap<String, Vec<String>> {
+    let mut grpc_headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in headers.iter() {
+        if is_hop_by_hop(key.as_str(), &[]) || key == "content-length" {
+            continue;
+        }
+        grpc_headers
+            .entry(key.to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).to_string());
+    }
+    grpc_headers
+}
+
+fn trailers(metadata: &HashMap<String, Value>) -> Result<Option<HeaderMap>, Box<dyn Error>> {
+    let trailers = match metadata.get(TRAILERS) {
+        Some(trailers) => serde_json::from_value::<HashMap<String, Vec<String>>>(trailers.clone())?,
+        None => return Ok(None),
+    };
+    let mut header_map = HeaderMap::new();
+    for (key, values) in trailers {
+        let name = HeaderName::from_bytes(key.as_bytes())?;
+        for value in values {
+            header_map.append(name.clone(), HeaderValue::from_str(&value)?);
+        }
+    }
+    Ok(Some(header_map))
+}
+
+/// A single uncompressed message of a known type is stored as json,
+/// anything else as the length prefixed messages read from the wire
+fn message_to_body(descriptor: Option<MessageDescriptor>, message: &Bytes) -> OptionalBody {
+    if message.is_empty() {
+        return OptionalBody::Empty;
+    }
+    if let (Some(descriptor), Some(frames)) = (descriptor, frames(message)) {
+        if let [(false, payload)] = frames.as_slice() {
+            match DynamicMessage::decode(descriptor, *payload)
+                .map_err(|e| e.to_string())
+                .and_then(|message| serde_json::to_vec(&message).map_err(|e| e.to_string()))
+            {
+                Ok(json) => {
+                    return OptionalBody::Present(
+                        json.into(),
+                        Some(ContentType::parse("application/json").unwrap()),
+                        None,
+                    )
+                }
+                Err(e) => debug!("Message is stored binary: {}", e),
+            }
+        }
+    }
+    OptionalBody::Present(
+        message.clone(),
+        Some(ContentType::parse("application/grpc").unwrap()),
+        Some(ContentTypeHint::BINARY),
+    )
+}
+
+fn body_to_message(
+    descriptor: Option<MessageDescriptor>,
+    body: &OptionalBody,
+) -> Result<Bytes, Box<dyn Error>> {
+    match body {
+        OptionalBody::Present(json, Some(content_type), _) if content_type.is_json() => {
+            let descriptor = descriptor.ok_or("A descriptor set is needed for json messages")?;
+            let mut deserializer = serde_json::Deserializer::from_slice(json);
+            let message = DynamicMessage::deserialize(descriptor, &mut deserializer)?;
+            Ok(frame(&message.encode_to_vec()))
+        }
+        OptionalBody::Present(message, ..) => Ok(message.clone()),
+        _ => Ok(Bytes::new()),
+    }
+}
+
+/// The json of the message when it is readable, otherwise the hash of its bytes
+fn request_key(body: &OptionalBody) -> String {
+    match body {
+        OptionalBody::Present(json, Some(content_type), _) if content_type.is_json() => {
+            match serde_json::from_slice::<Value>(json) {
+                Ok(json) => json.to_string(),
+                Err(_) => format!("sha256:{:x}", Sha256::digest(json)),
+            }
+        }
+        OptionalBody::Present(message, ..) => format!("sha256:{:x}", Sha256::digest(message)),
+        _ => String::new(),
+    }
+}
+
+/// Length prefixed messages, `None` when the bytes are not a sequence of messages
+fn frames(bytes: &[u8]) -> Option<Vec<(bool, &[u8])>> {
+    let mut frames = vec![];
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            return None;
+        }
+        let compressed = rest[0] == 1;
+        let length = u32::from_be_bytes(rest[1..5].try_into().ok()?) as usize;
+        let end = 5usize.checked_add(length)?;
+        if rest.len() < end {
+            return None;
+        }
+        frames.push((compressed, &rest[5..end]));
+        rest = &rest[end..];
+    }
+    Some(frames)
+}
+
+fn frame(payload: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(payload.len() + 5);
+    framed.put_u8(0);
+    framed.put_u32(payload.len() as u32);
+    framed.put_slice(payload);
+    framed.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::test_pacts_folder;
+    use prost_reflect::Value as MessageValue;
+    use prost_types::{
+        field_descriptor_proto, DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// `test.Greeter/Hello` taking a `HelloRequest { name }` and returning a `HelloReply { message }`
+    fn greeter_descriptor_set() -> Vec<u8> {
+        let message = |name: &str, field: &str| DescriptorProto {
+            name: Some(name.to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some(field.to_string()),
+                number: Some(1),
+                label: Some(field_descriptor_proto::Label::Optional as i32),
+                r#type: Some(field_descriptor_proto::Type::String as i32),
+                json_name: Some(field.to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("greeter.proto".to_string()),
+                package: Some("test".to_string()),
+                message_type: vec![
+                    message("HelloRequest", "name"),
+                    message("HelloReply", "message"),
+                ],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Greeter".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Hello".to_string()),
+                        input_type: Some(".test.HelloRequest".to_string()),
+                        output_type: Some(".test.HelloReply".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                syntax: Some("proto3".to_string()),
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn greeter_message(descriptors: &Descriptors, input: bool, text: &str) -> Bytes {
+        let method = descriptors.method("/test.Greeter/Hello").unwrap();
+        let (descriptor, field) = match input {
+            true => (method.input(), "name"),
+            false => (method.output(), "message"),
+        };
+        let mut message = DynamicMessage::new(descriptor);
+        message.set_field_by_name(field, MessageValue::String(text.to_string()));
+        frame(&message.encode_to_vec())
+    }
+
+    #[test]
+    fn test_message_body_round_trip() {
+        let descriptors = Descriptors::decode(&greeter_descriptor_set()).unwrap();
+        let method = descriptors.method("/test.Greeter/Hello").unwrap();
+        let message = greeter_message(&descriptors, true, "pact");
+        let body = message_to_body(Some(method.input()), &message);
+        assert_eq!(body.value().unwrap(), r#"{"name":"pact"}"#.as_bytes());
+        assert_eq!(request_key(&body), r#"{"name":"pact"}"#);
+        assert_eq!(
+            body_to_message(Some(method.input()), &body).unwrap(),
+            message
+        );
+
+        // without the descriptor the message is kept as it is
+        let body = message_to_body(None, &message);
+        assert_eq!(body_to_message(None, &body).unwrap(), message);
+        assert!(request_key(&body).starts_with("sha256:"));
+    }
+
+    /// Greeter upstream over h2c answering `Hello <name>`
+    async fn start_greeter(
+        descriptors: Descriptors,
+        hits: Arc<AtomicUsize>,
+    ) -> (SocketAddr, oneshot::Sender<()>) {
+        let make_service = make_service_fn(move |_| {
+            let descriptors = descriptors.clone();
+            let hits = hits.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let descriptors = descriptors.clone();
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let message = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let method = descriptors.method("/test.Greeter/Hello").unwrap();
+                        let request =
+                            DynamicMessage::decode(method.input(), &message[5..]).unwrap();
+                        let name = request.get_field_by_name("name").unwrap();
+                        let reply = greeter_message(
+                            &descriptors,
+                            false,
+                            &format!("Hello {}", name.as_str().unwrap()),
+                        );
+                        let mut trailers = HeaderMap::new();
+                        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                        let response = call_response(
+                            &HttpResponse {
+                                headers: Some(HashMap::from([(
+                                    "content-type".to_string(),
+                                    vec!["application/grpc".to_string()],
+                                )])),
+                                ..Default::default()
+                            },
+                            reply,
+                            Some(trailers),
+                        );
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            shutdown_receiver.await.ok();
+        }));
+        (addr, shutdown_sender)
+    }
+
+    async fn say_hello(
+        port: &str,
+        descriptors: &Descriptors,
+        name: &str,
+    ) -> (Bytes, Option<HeaderMap>) {
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let request = Request::post(format!("http://localhost:{port}/test.Greeter/Hello"))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(greeter_message(descriptors, true, name)))
+            .unwrap();
+        let mut body = client.request(request).await.unwrap().into_body();
+        let mut message = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            message.extend_from_slice(&chunk.unwrap());
+        }
+        (message.freeze(), body.trailers().await.unwrap())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_grpc() {
+        let descriptor_set = greeter_descriptor_set();
+        let descriptors = Descriptors::decode(&descriptor_set).unwrap();
+        let pacts_folder = test_pacts_folder();
+        let descriptor_set_path = pacts_folder.with_extension("pb");
+        std::fs::write(&descriptor_set_path, descriptor_set).unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (upstream_addr, upstream_shutdown) =
+            start_greeter(descriptors.clone(), hits.clone()).await;
+        let upstream = Url::parse(&format!("http://{upstream_addr}")).unwrap();
+        let expected = greeter_message(&descriptors, false, "Hello pact");
+
+        let mut pact_server = PactServer::with_grpc_server(
+            &pacts_folder,
+            None,
+            None,
+            upstream.clone(),
+            Some(&descriptor_set_path),
+        )
+        .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        for _ in 0..2 {
+            let (message, trailers) = say_hello(&port, &descriptors, "pact").await;
+            assert_eq!(message, expected);
+            assert_eq!(trailers.unwrap()["grpc-status"], "0");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        pact_server.stop().unwrap();
+        upstream_shutdown.send(()).unwrap();
+
+        // replayed without the upstream
+        let mut pact_server = PactServer::with_grpc_server(
+            &pacts_folder,
+            None,
+            None,
+            upstream,
+            Some(&descriptor_set_path),
+        )
+        .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let (message, trailers) = say_hello(&port, &descriptors, "pact").await;
+        assert_eq!(message, expected);
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+        pact_server.stop().unwrap();
+
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains(r#""test.Greeter/Hello {\"name\":\"pact\"}""#));
+        assert!(pact.contains(r#""message": "Hello pact""#));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{PassedClient, ProxyConfig, RegisteredConnection};
+use crate::listener::Stream;
+use std::error::Error;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+pub(crate) const MAX_HEADERS: usize = 100;
+
+/// Write half of a client connection whose requests are passed through
+pub(crate) trait ClientWriter: Write + Send {
+    /// Closes the connection for writing, the client sees it as closed
+    fn shutdown_write(&mut self);
+}
+
+impl ClientWriter for Stream {
+    fn shutdown_write(&mut self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+/// Passes the requests of the connection through to the http server, until the client
+/// closes it or sends a request that `serves` claims for the front, whose head is returned
+pub(crate) fn pass_through<R, W, F>(
+    head: Vec<u8>,
+    reader: &mut R,
+    writer: &mut W,
+    client: PassedClient,
+    backend_addr: SocketAddr,
+    config: &ProxyConfig,
+    serves: F,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>>
+where
+    R: BufRead,
+    W: ClientWriter,
+    F: Fn(&httparse::Request) -> bool,
+{
+    let mut backend = TcpStream::connect(backend_addr)?;
+    let connection = config
+        .passed_connections
+        .register(backend.local_addr()?, client);
+    let mut backend_reader = backend.try_clone()?;
+    let handed_over = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let responses = scope.spawn(|| {
+            let _ = io::copy(&mut backend_reader, writer);
+            // the client sees the connections the http server closes as closed
+            if !handed_over.load(Ordering::SeqCst) {
+                writer.shutdown_write();
+            }
+        });
+        let next = forward_requests(head, reader, &mut backend, &connection, config, serves);
+        if let Ok(Some(_)) = next {
+            handed_over.store(true, Ordering::SeqCst);
+        }
+        // the http server closes the connection once it answered the forwarded requests
+        let _ = backend.shutdown(Shutdown::Write);
+        responses
+            .join()
+            .map_err(|_| "Pass through thread panicked")?;
+        next
+    })
+}
+
+fn forward_requests<R, F>(
+    mut head: Vec<u8>,
+    reader: &mut R,
+    backend: &mut TcpStream,
+    connection: &RegisteredConnection,
+    config: &ProxyConfig,
+    serves: F,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>>
+where
+    R: BufRead,
+    F: Fn(&httparse::Request) -> bool,
+{
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        parsed.parse(&head)?;
+        if serves(&parsed) {
+            return Ok(Some(head));
+        }
+        // counted before the http server can queue it
+        connection.forwarded(config.in_flight.enter());
+        backend.write_all(&head)?;
+        match body_length(parsed.headers)? {
+            BodyLength::Fixed(length) => {
+                if io::copy(&mut reader.by_ref().take(length), backend)? < length {
+                    return Err(closed_mid_request().into());
+                }
+            }
+            BodyLength::Chunked => copy_chunked_body(reader, backend)?,
+        }
+        head = match read_head(reader)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+    }
+}
+
+/// How the body of a request is delimited
+pub(crate) enum BodyLength {
+    Fixed(u64),
+    Chunked,
+}
+
+pub(crate) fn body_length(headers: &[httparse::Header]) -> Result<BodyLength, Box<dyn Error>> {
+    let mut length = BodyLength::Fixed(0);
+    for header in headers {
+        let value = std::str::from_utf8(header.value)?;
+        if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            if value.to_ascii_lowercase().contains("chunked") {
+                return Ok(BodyLength::Chunked);
+            }
+        } else if header.name.eq_ignore_ascii_case("Content-Length") {
+            length = BodyLength::Fixed(value.trim().parse()?);
+        }
+    }
+    Ok(length)
+}
+
+/// Size announced by the line in front of a chunk, 0 for the last chunk
+pub(crate) fn chunk_size(line: &[u8]) -> Result<u64, Box<dyn Error>> {
+    let size = std::str::from_utf8(line)?
+        .split(';')
+        .next()
+        .unwrap_or_default();
+    Ok(u64::from_str_radix(size.trim(), 16)?)
+}
+
+pub(crate) fn closed_mid_request() -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "Connection closed in the middle of a request",
+    )
+}
+
+/// Copies a chunked body, its trailer included
+fn copy_chunked_body<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let line = read_line(reader)?;
+        writer.write_all(&line)?;
+        let size = chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        // the chunk and its line ending
+        if io::copy(&mut reader.by_ref().take(size + 2), writer)? < size + 2 {
+            return Err(closed_mid_request().into());
+        }
+    }
+    // the trailer ends with an empty line
+    loop {
+        let line = read_line(reader)?;
+        writer.write_all(&line)?;
+        if matches!(line.as_slice(), b"\r\n" | b"\n") {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the content of a chunked body, without the chunk sizes and the trailer
+pub(crate) fn read_chunked_body<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut body = Vec::new();
+    loop {
+        let size = chunk_size(&read_line(reader)?)?;
+        if size == 0 {
+            break;
+        }
+        if reader.by_ref().take(size).read_to_end(&mut body)? < size as usize {
+            return Err(closed_mid_request().into());
+        }
+        // the line ending of the chunk
+        read_line(reader)?;
+    }
+    // the trailer ends with an empty line
+    while !matches!(read_line(reader)?.as_slice(), b"\r\n" | b"\n") {}
+    Ok(body)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Err(closed_mid_request());
+    }
+    Ok(line)
+}
+
+/// Reads the request line and the headers, `None` when the client closed the connection
+pub(crate) fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        let read = match reader.read_until(b'\n', &mut head) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && head.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if read == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(closed_mid_request());
+        }
+        if head.ends_with(b"\r\n\r\n") {
+            return Ok(Some(head));
+        }
+        // skip empty lines in front of the request line
+        if head == b"\r\n" {
+            head.clear();
+        }
+    }
+}
@@ -1,8 +1,20 @@
 #![feature(fn_traits)]
 
+pub mod builder;
 pub mod cli;
+pub mod config;
+pub mod grpc;
+pub mod http1;
+pub mod listener;
+pub mod mitm;
 pub mod pact;
 pub mod pacts;
+pub mod routing;
 pub mod server;
+pub mod shared;
+#[cfg(test)]
+mod test_utils;
+pub mod tls;
 pub mod utils;
 pub mod web;
+pub mod websocket;
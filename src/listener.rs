@@ -0,0 +1,360 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address the proxy listens on, e.g. `localhost:8080`, `0.0.0.0:8080`, `[::]:8080`
+/// or `unix:/tmp/pact-proxy.sock`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn localhost(port: &str) -> Self {
+        ListenAddr::Tcp("localhost:".to_owned() + port)
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = Box<dyn Error>;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err("Unix socket should have a path".into());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        if addr.to_socket_addrs()?.next().is_none() {
+            return Err(format!("{addr} resolves to no address").into());
+        }
+        Ok(ListenAddr::Tcp(addr.to_string()))
+    }
+}
+
+/// Address a listener is bound to, the port is the one assigned when 0 was asked for
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl LocalAddr {
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            LocalAddr::Tcp(addr) => Some(addr.port()),
+            LocalAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalAddr::Tcp(addr) => write!(f, "{addr}"),
+            LocalAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds the address, a socket file left over by a previous run is replaced
+    pub fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // only a socket nobody listens on is replaced, never another kind of file
+                let is_socket = std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket());
+                if is_socket && UnixStream::connect(path).is_err() {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<LocalAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(LocalAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(LocalAddr::Unix(path.clone())),
+        }
+    }
+
+    /// Removes the socket file, the accepted connections are kept. A file left behind
+    /// without a listener is replaced by the next bind
+    pub fn unlink(&self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Connection accepted by a `Listener`
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(addr: &LocalAddr) -> io::Result<Self> {
+        match addr {
+            LocalAddr::Tcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            LocalAddr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            LocalAddr::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    /// Address of the client, `None` for unix sockets
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Hands the connection over to tokio, within a runtime
+    pub fn into_async(self) -> io::Result<AsyncStream> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(AsyncStream::Tcp(tokio::net::TcpStream::from_std(stream)?))
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                stream.set_nonblocking(true)?;
+                Ok(AsyncStream::Unix(tokio::net::UnixStream::from_std(stream)?))
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Connection accepted by a `Listener`, served by tokio
+pub enum AsyncStream {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::test_pacts_folder;
+    use crate::test_utils::TestUpstream;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_listen_addr_from_str() {
+        assert_eq!(
+            ListenAddr::from_str("unix:/tmp/proxy.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("/tmp/proxy.sock"))
+        );
+        assert_eq!(
+            ListenAddr::from_str("[::]:8080").unwrap(),
+            ListenAddr::Tcp("[::]:8080".to_string())
+        );
+        assert!(ListenAddr::from_str("0.0.0.0").is_err());
+        assert!(ListenAddr::from_str("unix:").is_err());
+        assert_eq!(
+            LocalAddr::Unix(PathBuf::from("/tmp/proxy.sock")).to_string(),
+            "unix:/tmp/proxy.sock"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_replaces_only_stale_sockets() {
+        let path = test_pacts_folder();
+        let addr = ListenAddr::Unix(path.to_path_buf());
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(Listener::bind(&addr).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        // left over by a run that did not remove it
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&addr).unwrap();
+        // in use by the listener
+        assert!(Listener::bind(&addr).is_err());
+        drop(listener);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_listen_addrs() {
+        use std::io::{Read, Write};
+        let upstream = TestUpstream::start(|_| Response::from_string("listening").boxed());
+        let pacts_folder = test_pacts_folder();
+        let addr = ListenAddr::from_str("[::1]:0").unwrap();
+        let mut pact_server = PactServer::listen(&pacts_folder, None, &addr, None).unwrap();
+        let local_addr = pact_server.local_addr().unwrap();
+        assert!(matches!(local_addr, LocalAddr::Tcp(addr) if addr.is_ipv6() && addr.port() != 0));
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://{local_addr}/http/127.0.0.1:{}/v6", upstream.port);
+        let response = reqwest::get(url).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "listening");
+        pact_server.stop().unwrap();
+
+        // the routes listen on the same host
+        let mut pact_server = PactServer::listen(&pacts_folder, None, &addr, None).unwrap();
+        let route = format!("0=http://127.0.0.1:{}", upstream.port);
+        let route_port = pact_server.add_route(route.parse().unwrap()).unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let response = reqwest::get(format!("http://[::1]:{route_port}/v6"))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "listening");
+        pact_server.stop().unwrap();
+
+        let socket_path = pacts_folder.with_extension("sock");
+        let addr = ListenAddr::from_str(&format!("unix:{}", socket_path.display())).unwrap();
+        let mut pact_server = PactServer::listen(&pacts_folder, None, &addr, None).unwrap();
+        assert_eq!(
+            pact_server.local_addr().unwrap(),
+            LocalAddr::Unix(socket_path.clone())
+        );
+        assert!(pact_server.port().is_err());
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let mut stream = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+        write!(
+            stream,
+            "GET /http/127.0.0.1:{}/v6 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            upstream.port
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("listening"), "{response}");
+        pact_server.stop().unwrap();
+        // served from the pact recorded over ipv6
+        assert_eq!(upstream.hits(), 1);
+        assert!(!socket_path.exists());
+    }
+}
@@ -1,4 +1,3 @@
-#![feature(async_closure)]
 #![feature(fn_traits)]
 #![cfg_attr(feature = "flame_it", feature(proc_macro_hygiene))]
 #[cfg(feature = "flame_it")]
@@ -7,30 +6,93 @@ extern crate flame;
 #[macro_use]
 extern crate flamer;
 
-mod cli;
-mod pact;
-mod pacts;
-mod server;
-mod utils;
-mod web;
-
-use crate::server::PactServer;
+use pact_proxy_rs::cli;
+use pact_proxy_rs::server::PactServer;
+use pact_proxy_rs::utils;
 
 #[tokio::main]
 async fn main() {
-    let (pact_files_folder, port) = match cli::get_commandline_args() {
-        Ok((pact_file, port)) => (pact_file, port),
+    let args = match cli::get_commandline_args() {
+        Ok(args) => args,
         Err(e) => {
-            println!("Error: {e}");
+            eprintln!("Error: {e}");
             return;
         }
     };
+    // stdout is kept for the readiness line
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
+        .with_writer(std::io::stderr)
         .init();
 
-    let mut pact_server = PactServer::with_http_server(&pact_files_folder, None, Some(port))
-        .expect("Error creating pact server");
+    let mut builder = PactServer::builder().pacts_folder(&args.pact_files_folder);
+    if let Some(port) = args.port {
+        builder = builder.port(port);
+    }
+    if let Some(addr) = args.bind {
+        builder = builder.bind(addr);
+    }
+    if let Some(ca_folder) = args.ca_folder {
+        builder = builder.intercept_https(ca_folder);
+    }
+    if let Some(grpc_upstream) = args.grpc_upstream {
+        builder = builder.grpc(grpc_upstream, args.descriptor_set);
+    }
+    if let Some(certificate) = args.tls {
+        builder = builder.tls(certificate);
+    }
+    if args.rewrite_urls {
+        builder = builder.rewrite_upstream_urls();
+    }
+    if args.record_redirects {
+        builder = builder.record_redirects();
+    }
+    if args.exact_bodies {
+        builder = builder.keep_exact_bodies();
+    }
+    if let Some(via) = &args.via {
+        builder = builder.via(via);
+    }
+    if args.forwarded_headers {
+        builder = builder.forwarded_headers();
+    }
+    if let Some(bytes) = args.spill_bodies_over {
+        builder = builder.spill_bodies_over(bytes);
+    }
+    if let Some(factor) = args.speed_up_streams {
+        builder = builder.speed_up_streams(factor);
+    }
+    if let Some(end) = args.sequence {
+        builder = builder.sequence(end);
+    }
+    let mut pact_server = match builder.build() {
+        Ok(pact_server) => pact_server,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return;
+        }
+    };
+    for route in args.routes {
+        pact_server.add_route(route).expect("Error adding route");
+    }
+    if let Some(port) = args.host_routing_port {
+        pact_server
+            .add_host_header_routing(Some(port), args.schemes)
+            .expect("Error adding host header routing");
+    }
+    let readiness = pact_server
+        .readiness()
+        .expect("Error reading the listener addresses");
+    if let Some(port_file) = &args.port_file {
+        let local_addr = pact_server.local_addr().expect("Error reading the address");
+        let contents = local_addr
+            .port()
+            .map_or_else(|| local_addr.to_string(), |port| port.to_string());
+        utils::write_atomically(port_file, &contents).expect("Error writing the port file");
+    }
+    // the only line on stdout, for test runners waiting for the proxy,
+    // it lists the route ports and the certificates too
+    println!("{readiness}");
     pact_server
         .start_blocking()
         .await
@@ -0,0 +1,694 @@
+use crate::config::{PassedClient, ProxyConfig};
+use crate::http1::{
+    self, body_length, closed_mid_request, read_chunked_body, read_head, BodyLength, MAX_HEADERS,
+};
+use crate::listener::{ListenAddr, Listener, LocalAddr, Stream};
+use crate::pact::headers::add_forwarded_headers;
+use crate::pact::request_to_pact::tunneled_request_to_pact;
+use crate::pact::session;
+use crate::pacts::Pacts;
+use crate::server::{self, ClientResponse};
+use crate::tls;
+use crate::utils;
+use crate::websocket;
+use pact_models::v4::http_parts::HttpRequest;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose,
+    SanType,
+};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tiny_http::{HTTPVersion, Header, Method};
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+use url::Url;
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+const CA_COMMON_NAME: &str = "pact-proxy-rs CA";
+
+/// Local certificate authority that signs the per host certificates
+pub struct CertificateAuthority {
+    ca: Certificate,
+    cert_path: PathBuf,
+}
+
+impl CertificateAuthority {
+    /// Loads the CA from the folder, generates and writes it there if it does not exist yet
+    pub fn load_or_generate(ca_folder: &Path) -> Result<Self, Box<dyn Error>> {
+        utils::create_folder_if_not_exists(ca_folder)?;
+        let cert_path = ca_folder.join(CA_CERT_FILE);
+        let key_path = ca_folder.join(CA_KEY_FILE);
+        let ca = if cert_path.exists() && key_path.exists() {
+            debug!("Loading CA from {:?}", cert_path);
+            let key_pair = KeyPair::from_pem(&std::fs::read_to_string(&key_path)?)?;
+            let params = CertificateParams::from_ca_cert_pem(
+                &std::fs::read_to_string(&cert_path)?,
+                key_pair,
+            )?;
+            Certificate::from_params(params)?
+        } else {
+            debug!("Generating CA into {:?}", cert_path);
+            let ca = Certificate::from_params(Self::ca_params())?;
+            std::fs::write(&cert_path, ca.serialize_pem()?)?;
+            utils::write_private_key(&key_path, &ca.serialize_private_key_pem())?;
+            ca
+        };
+        Ok(CertificateAuthority { ca, cert_path })
+    }
+
+    pub fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    fn ca_params() -> CertificateParams {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, CA_COMMON_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        params
+    }
+
+    /// Returns the der encoded certificate and private key for the host
+    pub fn leaf_certificate(&self, host: &str) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.subject_alt_names = vec![match IpAddr::from_str(host) {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.to_string()),
+        }];
+        params.use_authority_key_identifier_extension = true;
+        let leaf = Certificate::from_params(params)?;
+        Ok((
+            leaf.serialize_der_with_signer(&self.ca)?,
+            leaf.serialize_private_key_der(),
+        ))
+    }
+}
+
+/// Terminates tls of the CONNECT tunnels with certificates signed by the local CA
+pub struct Interceptor {
+    ca: CertificateAuthority,
+    server_configs: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl Interceptor {
+    pub fn new(ca_folder: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Interceptor {
+            ca: CertificateAuthority::load_or_generate(ca_folder)?,
+            server_configs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn ca_cert_path(&self) -> &Path {
+        self.ca.cert_path()
+    }
+
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+        let mut server_configs = self.server_configs.lock().unwrap();
+        if let Some(server_config) = server_configs.get(host) {
+            return Ok(server_config.clone());
+        }
+        let (cert, key) = self.ca.leaf_certificate(host)?;
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))?;
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let server_config = Arc::new(server_config);
+        server_configs.insert(host.to_string(), server_config.clone());
+        Ok(server_config)
+    }
+}
+
+/// Accepts the connections in front of the http server. Websocket upgrades, and CONNECT
+/// tunnels when https is intercepted, are served here. Every other connection is passed
+/// through to the http server, after terminating tls when the proxy serves https
+pub struct Front {
+    listener: Listener,
+    backend_addr: SocketAddr,
+    interceptor: Option<Arc<Interceptor>>,
+    tls: Option<TlsAcceptor>,
+    stopped: AtomicBool,
+}
+
+impl Front {
+    pub fn bind(
+        addr: &ListenAddr,
+        backend_addr: SocketAddr,
+        interceptor: Option<Arc<Interceptor>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Front {
+            listener: Listener::bind(addr)?,
+            backend_addr,
+            interceptor,
+            tls: None,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Accepts https instead of plain http connections
+    pub fn serve_tls(&mut self, server_config: Arc<ServerConfig>) {
+        self.tls = Some(TlsAcceptor::from(server_config));
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn local_addr(&self) -> io::Result<LocalAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn ca_cert_path(&self) -> Option<&Path> {
+        Some(self.interceptor.as_ref()?.ca_cert_path())
+    }
+
+    pub fn spawn(front: Arc<Front>, pacts: Arc<Pacts>, config: Arc<ProxyConfig>) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            let stream = front.listener.accept();
+            if front.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+            let front = front.clone();
+            let pacts = pacts.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                if let Err(e) = front.serve_connection(stream, pacts, config) {
+                    debug!("Connection error: {}", e);
+                }
+            });
+        })
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // connect to ourselves to unblock the accept loop
+        if let Ok(addr) = self.local_addr() {
+            let _ = Stream::connect(&addr);
+        }
+        self.listener.unlink();
+    }
+
+    fn serve_connection(
+        &self,
+        stream: Stream,
+        pacts: Arc<Pacts>,
+        config: Arc<ProxyConfig>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(acceptor) = &self.tls {
+            return tls::serve_connection(acceptor, stream, self.backend_addr, pacts, config);
+        }
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut head = match read_head(&mut reader)? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut parsed = httparse::Request::new(&mut headers);
+            parsed.parse(&head)?;
+            if websocket::is_upgrade(parsed.headers) {
+                let target = parsed.path.ok_or("request should have path")?;
+                return websocket::serve_upgrade(
+                    target,
+                    parsed.headers,
+                    reader,
+                    stream,
+                    pacts,
+                    config,
+                );
+            }
+            if let (Some("CONNECT"), Some(interceptor)) = (parsed.method, &self.interceptor) {
+                let authority = parsed
+                    .path
+                    .ok_or("CONNECT should have authority")?
+                    .to_string();
+                let mut writer = stream;
+                writer.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+                writer.flush()?;
+                let client_addr = writer.peer_addr();
+                return run_tunnel(
+                    interceptor,
+                    &authority,
+                    client_addr,
+                    Duplex { reader, writer },
+                    pacts,
+                    config,
+                );
+            }
+            let client = PassedClient {
+                addr: stream.peer_addr(),
+                proto: "http",
+            };
+            head = match http1::pass_through(
+                head,
+                &mut reader,
+                &mut stream.try_clone()?,
+                client,
+                self.backend_addr,
+                &config,
+                |request| self.serves(request),
+            )? {
+                Some(head) => head,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Whether the request is served by the front instead of the http server
+    fn serves(&self, request: &httparse::Request) -> bool {
+        websocket::is_upgrade(request.headers)
+            || (request.method == Some("CONNECT") && self.interceptor.is_some())
+    }
+}
+
+/// Read and write halves of the client connection
+struct Duplex<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: Read, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn run_tunnel<S: Read + Write + Send>(
+    interceptor: &Interceptor,
+    authority: &str,
+    client_addr: Option<SocketAddr>,
+    stream: S,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let host = Url::parse(&format!("https://{authority}"))?
+        .host_str()
+        .ok_or("CONNECT should have host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    debug!("Intercepting tunnel to {}", authority);
+    let server_config = interceptor.server_config(&host)?;
+    let tls_stream = StreamOwned::new(ServerConnection::new(server_config)?, stream);
+    let mut tls_stream = BufReader::new(tls_stream);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    while let Some(mut tunneled_request) = read_tunneled_request(&mut tls_stream)? {
+        let _in_flight = config.in_flight.enter();
+        let pact_request =
+            tunneled_pact_request(&mut tunneled_request, authority, client_addr, &config);
+        // a failing request is answered with an error, the tunnel goes on with the next ones
+        let response = match pact_request {
+            Ok(pact_request) => runtime
+                .block_on(server::get_response(&pacts, &config, &pact_request))
+                .unwrap_or_else(|e| {
+                    ClientResponse::Buffered(server::error_response(502, e.as_ref()))
+                }),
+            Err(e) => ClientResponse::Buffered(server::error_response(400, e.as_ref())),
+        };
+        let writer = tls_stream.get_mut();
+        match response {
+            ClientResponse::Buffered(response) => response.raw_print(
+                &mut *writer,
+                HTTPVersion(1, 1),
+                &tunneled_request.headers,
+                tunneled_request.method == Method::Head,
+                None,
+            )?,
+            ClientResponse::Paced(response) => response.write_to(writer)?,
+            // the runtime of the tunnel reads the upstream while the stream is written
+            ClientResponse::Relayed(response, recording) => thread::scope(|scope| {
+                let writer = &mut *writer;
+                let written = scope.spawn(move || response.write_to(writer));
+                runtime.block_on(recording);
+                written
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("The stream writer panicked")))
+            })?,
+        }
+        writer.flush()?;
+        if tunneled_request.closes_connection() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn tunneled_pact_request(
+    tunneled_request: &mut TunneledRequest,
+    authority: &str,
+    client_addr: Option<SocketAddr>,
+    config: &ProxyConfig,
+) -> Result<HttpRequest, Box<dyn Error>> {
+    let (session, target) = match session::strip_session_prefix(&tunneled_request.target) {
+        Some((session, target)) => (Some(session), target),
+        None => (None, tunneled_request.target.as_str()),
+    };
+    let url = Url::parse(&format!("https://{authority}{target}"))?;
+    let mut pact_request = tunneled_request_to_pact(
+        &tunneled_request.method,
+        url,
+        &tunneled_request.headers,
+        std::mem::take(&mut tunneled_request.body),
+    )?;
+    if let Some(session) = session {
+        session::add_session_header(&mut pact_request, session);
+    }
+    if config.forwarded_headers {
+        let host = tunneled_request
+            .headers
+            .iter()
+            .find(|header| header.field.equiv("Host"))
+            .map(|header| header.value.to_string());
+        add_forwarded_headers(
+            &mut pact_request,
+            client_addr,
+            Some(host.as_deref().unwrap_or(authority)),
+            "https",
+        );
+    }
+    Ok(pact_request)
+}
+
+struct TunneledRequest {
+    method: Method,
+    target: String,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+}
+
+impl TunneledRequest {
+    fn closes_connection(&self) -> bool {
+        self.headers
+            .iter()
+            .any(|header| header.field.equiv("Connection") && header.value.as_str() == "close")
+    }
+}
+
+/// Reads one http/1.1 request from the decrypted stream, `None` when the client closed it
+fn read_tunneled_request<S: Read>(
+    reader: &mut BufReader<S>,
+) -> Result<Option<TunneledRequest>, Box<dyn Error>> {
+    let head = match read_head(reader)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if parsed.parse(&head)?.is_partial() {
+        return Err("Incomplete request in tunnel".into());
+    }
+    let method = Method::from_str(parsed.method.ok_or("request should have method")?)
+        .map_err(|_| "Unknown method")?;
+    let target = parsed.path.ok_or("request should have path")?.to_string();
+    let request_headers = parsed
+        .headers
+        .iter()
+        .map(|header| Header::from_bytes(header.name.as_bytes(), header.value))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Invalid header in tunnel")?;
+    // the body is read like the one of a passed through request, so that the next request
+    // of the tunnel starts right after it
+    let body = match body_length(parsed.headers)? {
+        BodyLength::Fixed(length) => {
+            let mut body = Vec::new();
+            if reader.by_ref().take(length).read_to_end(&mut body)? < length as usize {
+                return Err(closed_mid_request().into());
+            }
+            body
+        }
+        BodyLength::Chunked => read_chunked_body(reader)?,
+    };
+    Ok(Some(TunneledRequest {
+        method,
+        target,
+        headers: request_headers,
+        body,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Mode, UpstreamClient};
+    use crate::pact::edit;
+    use crate::server::{InteractionIndexMap, PactServer};
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use crate::tls::TlsCertificate;
+    use pact_models::bodies::OptionalBody;
+    use pact_models::prelude::v4::V4Pact;
+    use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+    use pact_models::{Consumer, Provider};
+
+    #[test]
+    fn test_ca_is_generated_once() {
+        let ca_folder = test_pacts_folder();
+        let ca = CertificateAuthority::load_or_generate(&ca_folder).unwrap();
+        let ca_pem = std::fs::read_to_string(ca.cert_path()).unwrap();
+        let reloaded_ca = CertificateAuthority::load_or_generate(&ca_folder).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(reloaded_ca.cert_path()).unwrap(),
+            ca_pem
+        );
+        assert!(reloaded_ca.leaf_certificate("localhost").is_ok());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = std::fs::metadata(ca_folder.join(CA_KEY_FILE)).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_https_interception_replay() {
+        let pacts_folder = test_pacts_folder();
+        let url = "https://localhost/intercepted";
+        let recorded_pact = |consumer: &str, body: &str| {
+            let mut pact = V4Pact {
+                consumer: Consumer {
+                    name: consumer.to_string(),
+                },
+                provider: Provider {
+                    name: "localhost".to_string(),
+                },
+                ..Default::default()
+            };
+            let mut interaction_index_map = InteractionIndexMap::new();
+            interaction_index_map.insert(
+                (consumer.to_string(), "localhost".to_string()),
+                HashMap::new(),
+            );
+            edit::add_interaction_to_pact(
+                url,
+                None,
+                &HttpRequest {
+                    method: "GET".to_string(),
+                    path: url.to_string(),
+                    ..Default::default()
+                },
+                &HttpResponse {
+                    status: 200,
+                    body: OptionalBody::Present(body.to_string().into(), None, None),
+                    ..Default::default()
+                },
+                HashMap::new(),
+                &mut pact,
+                &mut interaction_index_map,
+            )
+            .unwrap();
+            ((consumer.to_string(), "localhost".to_string()), pact)
+        };
+        let pacts = HashMap::from([
+            recorded_pact("consumer", "intercepted"),
+            recorded_pact("consumer@funded", "funded"),
+        ]);
+        let mut pact_server = PactServer::with_https_interception(
+            &pacts_folder,
+            Some(pacts),
+            None,
+            &pacts_folder.join("ca"),
+        )
+        .unwrap();
+        let ca_cert_path = pact_server.ca_cert_path().unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let ca_cert =
+            reqwest::Certificate::from_pem(&std::fs::read(ca_cert_path).unwrap()).unwrap();
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::https(format!("http://localhost:{port}")).unwrap())
+            .add_root_certificate(ca_cert)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let response = client.get(url).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.text().await.unwrap(), "intercepted");
+        }
+        // the sessions of the header and of the path prefix are served through the tunnel
+        let response = client
+            .get(url)
+            .header("X-Pact-Session", "funded")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "funded");
+        let response = client
+            .get("https://localhost/_session/funded/intercepted")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "funded");
+        // requests that are not tunneled are passed through to the http server
+        let upstream = TestUpstream::start(|_| tiny_http::Response::from_string("plain").boxed());
+        let plain_url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/plain",
+            upstream.port
+        );
+        let response = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(plain_url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        pact_server.stop().unwrap();
+    }
+
+    /// Sends the CONNECT request to the proxy and speaks http/1.1 through the tunnel
+    async fn open_tunnel(
+        port: &str,
+        authority: &str,
+        ca_cert_path: &Path,
+    ) -> hyper::client::conn::SendRequest<hyper::Body> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(format!("localhost:{port}"))
+            .await
+            .unwrap();
+        let connect = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+        stream.write_all(connect.as_bytes()).await.unwrap();
+        // nothing follows the answer before the client hello
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 200"));
+        let mut roots = rustls::RootCertStore::empty();
+        let pem = std::fs::read(ca_cert_path).unwrap();
+        for cert in rustls_pemfile::certs(&mut pem.as_slice()).unwrap() {
+            roots.add(&rustls::Certificate(cert)).unwrap();
+        }
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tls_stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let (sender, connection) = hyper::client::conn::handshake(tls_stream).await.unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_https_interception_record() {
+        let upstream = TestUpstream::start(|request| {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            tiny_http::Response::from_string(format!("{} {body}", request.method())).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        // the tunneled requests are recorded from an https upstream
+        let mut tls_upstream = PactServer::builder()
+            .pacts_folder(pacts_folder.with_extension("upstream"))
+            .tls(TlsCertificate::SelfSigned(
+                pacts_folder.with_extension("tls"),
+            ))
+            .build()
+            .unwrap();
+        let authority = format!("localhost:{}", tls_upstream.port().unwrap());
+        let mut tls_upstream = Some(tls_upstream.start_non_blocking().await.unwrap());
+        let echo_path = format!("/http/127.0.0.1:{}/echo", upstream.port);
+        for mode in [Mode::Record, Mode::Replay] {
+            let mut pact_server = PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .mode(mode)
+                .intercept_https(pacts_folder.join("ca"))
+                .upstream_client(UpstreamClient {
+                    accept_invalid_certs: true,
+                    ..Default::default()
+                })
+                .build()
+                .unwrap();
+            let port = pact_server.port().unwrap();
+            let ca_cert_path = pact_server.ca_cert_path().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            let mut tunnel = open_tunnel(&port, &authority, &ca_cert_path).await;
+            let (mut body_sender, body) = hyper::Body::channel();
+            tokio::spawn(async move {
+                body_sender.send_data("chunked ".into()).await.unwrap();
+                body_sender.send_data("body".into()).await.unwrap();
+            });
+            let request = hyper::Request::post(&echo_path)
+                .header("Host", &authority)
+                .body(body)
+                .unwrap();
+            let response = tunnel.send_request(request).await.unwrap();
+            assert_eq!(response.status(), 200);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "POST chunked body");
+            // the next request of the tunnel starts after the chunked body
+            let request = hyper::Request::put(&echo_path)
+                .header("Host", &authority)
+                .body("fixed body".into())
+                .unwrap();
+            let response = tunnel.send_request(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "PUT fixed body");
+            pact_server.stop().unwrap();
+            // the replay does not reach the upstream
+            if let Some(mut tls_upstream) = tls_upstream.take() {
+                tls_upstream.stop().unwrap();
+            }
+        }
+        assert_eq!(upstream.hits(), 2);
+    }
+}
@@ -0,0 +1,186 @@
+use pact_models::bodies::OptionalBody;
+use pact_models::v4::http_parts::HttpResponse;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+const BODIES_FOLDER: &str = "bodies";
+const BODY_FILE: &str = "bodyFile";
+
+/// Moves a body larger than `threshold` bytes to a file named by its hash in the `bodies`
+/// folder next to the pacts. Returns the reference of the file, relative to the pacts folder
+pub fn spill_body(
+    pacts_folder: &Path,
+    pact_response: &mut HttpResponse,
+    threshold: usize,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let body = match &pact_response.body {
+        OptionalBody::Present(body, ..) if body.len() > threshold => body,
+        _ => return Ok(None),
+    };
+    let reference = write_body_file(pacts_folder, body)?;
+    pact_response.body = OptionalBody::Missing;
+    Ok(Some(reference))
+}
+
+/// Identical bodies share the same file
+fn write_body_file(pacts_folder: &Path, body: &[u8]) -> Result<String, Box<dyn Error>> {
+    let hash = format!("{:x}", Sha256::digest(body));
+    let reference = BODIES_FOLDER.to_owned() + "/" + hash.as_str();
+    let path = pacts_folder.join(&reference);
+    if path.exists() {
+        debug!("Body file {} exists already", reference);
+        return Ok(reference);
+    }
+    fs::create_dir_all(pacts_folder.join(BODIES_FOLDER))?;
+    // written under a temporary name, so that a body file is never seen half written
+    let temporary_path = path.with_extension(format!("tmp-{}", rand::random::<u64>()));
+    fs::write(&temporary_path, body)?;
+    fs::rename(&temporary_path, &path)?;
+    debug!("Body written to {}", reference);
+    Ok(reference)
+}
+
+/// Copy of the response with the body read back from its file
+pub fn read_body_file(
+    pact_response: &HttpResponse,
+    body_file: &Path,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let body = fs::read(body_file)?;
+    Ok(HttpResponse {
+        body: OptionalBody::Present(body.into(), None, None),
+        ..pact_response.clone()
+    })
+}
+
+/// Metadata of the interaction referencing the body file
+pub fn body_file_metadata(reference: &str) -> HashMap<String, Value> {
+    HashMap::from([(BODY_FILE.to_string(), Value::String(reference.to_string()))])
+}
+
+pub fn body_file_reference(metadata: &HashMap<String, Value>) -> Option<&str> {
+    metadata.get(BODY_FILE)?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::PactServerBuilder;
+    use crate::config::Mode;
+    use crate::pact::encoding::Encoding;
+    use crate::server::PactServer;
+    use crate::test_utils::test_pacts_folder;
+    use crate::test_utils::{record_then_replay, TestUpstream};
+    use tiny_http::Response;
+
+    #[test]
+    fn test_spill_body() {
+        let pacts_folder = test_pacts_folder();
+        let response = |body: &str| HttpResponse {
+            body: OptionalBody::Present(body.as_bytes().to_vec().into(), None, None),
+            ..Default::default()
+        };
+        let mut small = response("small");
+        assert_eq!(spill_body(&pacts_folder, &mut small, 8).unwrap(), None);
+        assert!(small.body.is_present());
+
+        let mut large = response("large body");
+        let mut same_large = response("large body");
+        let reference = spill_body(&pacts_folder, &mut large, 8).unwrap().unwrap();
+        assert_eq!(
+            spill_body(&pacts_folder, &mut same_large, 8).unwrap(),
+            Some(reference.clone())
+        );
+        assert_eq!(large.body, OptionalBody::Missing);
+        assert_eq!(
+            fs::read(pacts_folder.join(&reference)).unwrap(),
+            b"large body"
+        );
+        assert_eq!(
+            fs::read_dir(pacts_folder.join(BODIES_FOLDER))
+                .unwrap()
+                .count(),
+            1
+        );
+        let metadata = body_file_metadata(&reference);
+        assert_eq!(body_file_reference(&metadata), Some(reference.as_str()));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_spill_bodies() {
+        let large_body = "market data ".repeat(1000);
+        let body = large_body.clone();
+        let upstream = TestUpstream::start(move |_| Response::from_string(body.as_str()).boxed());
+        let upstream_url = |upstream_port: u16| format!("http://127.0.0.1:{upstream_port}/next");
+        let linking = TestUpstream::start(move |request| {
+            let host = request.headers().iter().find(|h| h.field.equiv("Host"));
+            let port = host.unwrap().value.as_str().rsplit(':').next().unwrap();
+            let body = "market data ".repeat(100) + &upstream_url(port.parse().unwrap());
+            Response::from_string(body).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let (upstream, large_body) = (&upstream, &large_body);
+        let spill = |builder: PactServerBuilder| builder.spill_bodies_over(1024);
+        record_then_replay(&pacts_folder, spill, |port| async move {
+            for path in ["/dump/1", "/dump/2"] {
+                let response = reqwest::get(upstream.url(&port, path)).await.unwrap();
+                assert_eq!(&response.text().await.unwrap(), large_body);
+            }
+        })
+        .await;
+        assert_eq!(upstream.hits(), 2);
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(!pact.contains("market data"));
+        // the identical bodies share a file
+        assert_eq!(
+            std::fs::read_dir(pacts_folder.join("bodies"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        // a body file is rewritten and encoded like the bodies in the pact
+        for mode in [Mode::Record, Mode::Replay] {
+            let mut pact_server = PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .spill_bodies_over(1024)
+                .rewrite_upstream_urls()
+                .mode(mode)
+                .build()
+                .unwrap();
+            let port = pact_server.port().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            let url = format!(
+                "http://localhost:{port}/http/127.0.0.1:{}/linked",
+                linking.port
+            );
+            let response = reqwest::Client::new()
+                .get(url)
+                .header("accept-encoding", "gzip")
+                .send()
+                .await
+                .unwrap();
+            let encoded = response.headers().get("content-encoding").cloned();
+            let body = response.bytes().await.unwrap();
+            let body = match encoded {
+                Some(encoding) => {
+                    assert_eq!(encoding, "gzip");
+                    Encoding::Gzip.decode(&body).unwrap()
+                }
+                None => body.to_vec(),
+            };
+            let body = String::from_utf8(body).unwrap();
+            let rewritten = format!(
+                "http://localhost:{port}/http/127.0.0.1:{}/next",
+                linking.port
+            );
+            assert!(body.ends_with(&rewritten), "{mode:?} body not rewritten");
+            pact_server.stop().unwrap();
+        }
+        assert_eq!(linking.hits(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::pact::encoding::Encoding;
+use bytes::{Bytes, BytesMut};
+use pact_models::content_types::ContentType;
+use reqwest::Response;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const CHUNKS: &str = "chunks";
+const OFFSET_MILLIS: &str = "offsetMillis";
+const LENGTH: &str = "length";
+/// Bodies that clients read while they arrive, e.g. server-sent events
+const STREAMING_TYPES: [&str; 6] = [
+    "text/event-stream",
+    "application/x-ndjson",
+    "application/ndjson",
+    "application/jsonl",
+    "application/x-jsonlines",
+    "application/stream+json",
+];
+
+/// Part of a streamed body, `offset` is the time since the headers arrived
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub offset: Duration,
+    pub length: usize,
+}
+
+pub fn is_streaming(content_type: &ContentType) -> bool {
+    let mut essence = content_type.main_type.clone() + "/" + &content_type.sub_type;
+    if let Some(suffix) = &content_type.suffix {
+        essence += &("+".to_owned() + suffix);
+    }
+    STREAMING_TYPES.contains(&essence.to_lowercase().as_str())
+}
+
+/// Reads the body chunk by chunk and notes when each one arrived, every chunk is passed
+/// on to `relay` as it arrives. Stops early when `relay` returns false, e.g. the client left,
+/// then there is no complete body to return
+pub async fn read_chunks<F>(
+    response: &mut Response,
+    mut relay: F,
+) -> Result<Option<(Bytes, Vec<Chunk>)>, Box<dyn Error>>
+where
+    F: FnMut(Bytes) -> bool,
+{
+    let started = Instant::now();
+    let mut body = BytesMut::new();
+    let mut chunks = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        chunks.push(Chunk {
+            offset: started.elapsed(),
+            length: chunk.len(),
+        });
+        body.extend_from_slice(&chunk);
+        if !relay(chunk) {
+            debug!("Stream no longer relayed after {} chunks", chunks.len());
+            return Ok(None);
+        }
+    }
+    debug!("Streamed body of {} chunks", chunks.len());
+    Ok(Some((body.freeze(), chunks)))
+}
+
+/// The chunks of an encoded body with the lengths they have once it is decoded,
+/// the codings are listed in the order they were applied
+pub fn decoded_chunks(
+    body: &[u8],
+    chunks: &[Chunk],
+    encodings: &[Encoding],
+) -> io::Result<Vec<Chunk>> {
+    let mut decoders: Vec<_> = encodings
+        .iter()
+        .rev()
+        .map(Encoding::stream_decoder)
+        .collect();
+    let mut decoded_chunks = Vec::with_capacity(chunks.len());
+    let mut start = 0;
+    for chunk in chunks {
+        let mut decoded = body[start..start + chunk.length].to_vec();
+        start += chunk.length;
+        for decoder in decoders.iter_mut() {
+            decoded = decoder.decode(&decoded)?;
+        }
+        decoded_chunks.push(Chunk {
+            offset: chunk.offset,
+            length: decoded.len(),
+        });
+    }
+    // the bytes the decoders held back end the last chunk
+    let mut rest = vec![];
+    for mut decoder in decoders {
+        let mut decoded = decoder.decode(&rest)?;
+        decoded.extend(decoder.finish()?);
+        rest = decoded;
+    }
+    if let Some(last) = decoded_chunks.last_mut() {
+        last.length += rest.len();
+    }
+    Ok(decoded_chunks)
+}
+
+/// Metadata of the interaction with the arrival times of the chunks
+pub fn chunks_metadata(chunks: &[Chunk]) -> HashMap<String, Value> {
+    let chunks = chunks
+        .iter()
+        .map(
+            |chunk| json!({ OFFSET_MILLIS: chunk.offset.as_millis() as u64, LENGTH: chunk.length }),
+        )
+        .collect();
+    HashMap::from([(CHUNKS.to_string(), Value::Array(chunks))])
+}
+
+pub fn recorded_chunks(metadata: &HashMap<String, Value>) -> Option<Vec<Chunk>> {
+    metadata
+        .get(CHUNKS)?
+        .as_array()?
+        .iter()
+        .map(|chunk| {
+            Some(Chunk {
+                offset: Duration::from_millis(chunk.get(OFFSET_MILLIS)?.as_u64()?),
+                length: chunk.get(LENGTH)?.as_u64()? as usize,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::test_pacts_folder;
+    use std::thread;
+
+    #[test]
+    fn test_chunks_metadata() {
+        let chunks = vec![
+            Chunk {
+                offset: Duration::from_millis(3),
+                length: 10,
+            },
+            Chunk {
+                offset: Duration::from_millis(250),
+                length: 4,
+            },
+        ];
+        assert_eq!(recorded_chunks(&chunks_metadata(&chunks)), Some(chunks));
+        assert_eq!(recorded_chunks(&HashMap::new()), None);
+        assert!(is_streaming(
+            &ContentType::parse("text/event-stream; charset=utf-8").unwrap()
+        ));
+        assert!(is_streaming(
+            &ContentType::parse("application/stream+json").unwrap()
+        ));
+        assert!(!is_streaming(
+            &ContentType::parse("application/json").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_decoded_chunks() {
+        let events = ["data: 1\n\n", "data: 22\n\n", "data: 333\n\n"];
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let mut encoder = encoding.stream_encoder();
+            let mut body = vec![];
+            let mut chunks = vec![];
+            for (index, event) in events.iter().enumerate() {
+                let encoded = encoder.encode(event.as_bytes()).unwrap();
+                chunks.push(Chunk {
+                    offset: Duration::from_millis(100 * index as u64),
+                    length: encoded.len(),
+                });
+                body.extend(encoded);
+            }
+            let end = encoder.finish().unwrap();
+            chunks.last_mut().unwrap().length += end.len();
+            body.extend(end);
+            let decoded = decoded_chunks(&body, &chunks, &[encoding]).unwrap();
+            let lengths: Vec<_> = decoded.iter().map(|chunk| chunk.length).collect();
+            assert_eq!(lengths, events.map(str::len), "{encoding:?}");
+            assert_eq!(decoded[2].offset, Duration::from_millis(200));
+        }
+    }
+
+    /// Upstream sending server-sent events 100ms apart on a single connection, it stops
+    /// once the proxy closes the connection
+    fn start_event_stream_upstream(events: usize) -> (u16, thread::JoinHandle<()>) {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n",
+                )
+                .unwrap();
+            for event in 0..events {
+                thread::sleep(std::time::Duration::from_millis(100));
+                let data = format!("data: {event}\n\n");
+                if write!(stream, "{:x}\r\n{data}\r\n", data.len()).is_err() {
+                    return;
+                }
+            }
+            let _ = stream.write_all(b"0\r\n\r\n");
+        });
+        (port, thread)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_event_stream_timing() {
+        let (upstream_port, upstream) = start_event_stream_upstream(3);
+        let pacts_folder = test_pacts_folder();
+        // recorded, replayed with the original timing and replayed faster
+        let mut spans = vec![];
+        for speed_up in [None, None, Some(3.0)] {
+            let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+            if let Some(speed_up) = speed_up {
+                pact_server.speed_up_streams(speed_up);
+            }
+            let port = pact_server.port().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            let url = format!("http://localhost:{port}/http/127.0.0.1:{upstream_port}/events");
+            let started = std::time::Instant::now();
+            let mut response = reqwest::get(url).await.unwrap();
+            let mut events = vec![];
+            while let Some(chunk) = response.chunk().await.unwrap() {
+                events.push((started.elapsed(), chunk));
+            }
+            let body: Vec<u8> = events
+                .iter()
+                .flat_map(|(_, chunk)| chunk.to_vec())
+                .collect();
+            assert_eq!(body, b"data: 0\n\ndata: 1\n\ndata: 2\n\n");
+            assert!(events.len() > 1, "the events arrived at once");
+            // time between the first and the last event, the upstream sends them 200ms apart
+            spans.push(events[events.len() - 1].0 - events[0].0);
+            pact_server.stop().unwrap();
+        }
+        let [recorded, replayed, faster] = spans[..] else {
+            unreachable!()
+        };
+        // relayed while it is recorded instead of buffered first, then paced once
+        assert!(recorded.as_millis() >= 100, "recorded over {recorded:?}");
+        assert!(replayed * 2 >= recorded, "replayed over {replayed:?}");
+        assert!(replayed <= recorded * 2, "replayed over {replayed:?}");
+        assert!(faster * 3 <= replayed * 2, "sped up over {faster:?}");
+        upstream.join().unwrap();
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains("offsetMillis"));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_cut_off_stream_is_not_recorded() {
+        let (upstream_port, upstream) = start_event_stream_upstream(20);
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://localhost:{port}/http/127.0.0.1:{upstream_port}/events");
+        let mut response = reqwest::get(url).await.unwrap();
+        let first = response.chunk().await.unwrap().unwrap();
+        assert_eq!(first, "data: 0\n\n");
+        // the client leaves, the proxy stops reading the upstream before its end
+        drop(response);
+        // waited for off the runtime, which closes the connection of the dropped response
+        tokio::task::spawn_blocking(move || upstream.join().unwrap())
+            .await
+            .unwrap();
+        pact_server.stop().unwrap();
+        let pact_path = pacts_folder.join("consumer-127.0.0.1.json");
+        let pact = std::fs::read_to_string(pact_path).unwrap_or_default();
+        assert!(!pact.contains("/events"), "{pact}");
+    }
+}
@@ -1,22 +1,32 @@
-use crate::server::InteractionIndexMap;
+use crate::server::{InteractionIndexMap, InteractionKey};
 use crate::utils;
 #[cfg(feature = "flame_it")]
 use flame as f;
 #[cfg(feature = "flame_it")]
 use flamer::flame;
+use pact_models::bodies::OptionalBody;
+use pact_models::interaction::Interaction;
 use pact_models::pact::Pact;
 use pact_models::pact::{read_pact, write_pact};
 use pact_models::prelude::v4::SynchronousHttp;
+use pact_models::provider_states::ProviderState;
+use pact_models::v4::async_message::AsynchronousMessage;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::message_parts::MessageContents;
 use pact_models::v4::pact::V4Pact;
 use pact_models::PactSpecification;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use tracing::debug;
 use url::Url;
 
-const CONSUMER_NAME: &str = "consumer";
+/// Consumer of the pacts unless another name is configured
+pub const CONSUMER_NAME: &str = "consumer";
+/// Key of the proxy's own data in the plugin configuration of the interactions
+pub const PROXY_PLUGIN: &str = "pact-proxy-rs";
 
 pub fn save_pact_to_file(pact: &V4Pact, pact_path: &Path) -> Result<(), Box<dyn Error>> {
     write_pact(pact.boxed(), pact_path, PactSpecification::V4, true)?;
@@ -60,44 +70,102 @@ pub fn read_pacts(
     Ok(pacts)
 }
 
+/// Description of the interaction of a request, the url of a GET without a body,
+/// otherwise the method, the url and the hash of the body
+pub fn interaction_description(pact_request: &HttpRequest) -> String {
+    let description = match pact_request.method.as_str() {
+        "GET" => pact_request.path.clone(),
+        method => format!("{method} {}", pact_request.path),
+    };
+    match &pact_request.body {
+        OptionalBody::Present(body, ..) if !body.is_empty() => {
+            format!("{description} sha256:{:x}", Sha256::digest(body))
+        }
+        _ => description,
+    }
+}
+
+/// Key of an interaction in the `InteractionIndexMap`, the same request is recorded
+/// once per provider state
+pub fn interaction_key(description: &str, provider_state: Option<&str>) -> InteractionKey {
+    (description.to_string(), provider_state.map(str::to_string))
+}
+
 #[cfg_attr(feature = "flame_it", flame)]
 pub fn add_interaction_to_pact(
+    description: &str,
+    provider_state: Option<&str>,
     pact_request: &HttpRequest,
     pact_response: &HttpResponse,
+    metadata: HashMap<String, Value>,
     pact: &mut V4Pact,
     interaction_index_map: &mut InteractionIndexMap,
 ) -> Result<(), Box<dyn Error>> {
     let interaction = SynchronousHttp {
         id: None,
         key: None,
-        description: pact_request.path.clone(),
-        provider_states: Vec::new(),
+        description: description.to_string(),
+        provider_states: provider_state
+            .map(ProviderState::default)
+            .into_iter()
+            .collect(),
         request: pact_request.clone(),
         response: pact_response.clone(),
         comments: Default::default(),
         pending: false,
-        plugin_config: Default::default(),
+        plugin_config: plugin_config(metadata),
         interaction_markup: Default::default(),
         transport: None,
     };
 
-    add_interaction_and_amend_index(pact_request, pact, interaction_index_map, &interaction)?;
+    let key = interaction_key(description, provider_state);
+    add_interaction_and_amend_index(key, pact, interaction_index_map, &interaction)?;
 
     Ok(())
 }
 
+/// Adds an asynchronous message, e.g. a websocket frame. The proxy is registered in the
+/// plugin data of the pact since the message is only usable with its metadata
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn add_message_to_pact(
+    description: &str,
+    contents: &MessageContents,
+    metadata: HashMap<String, Value>,
+    pact: &mut V4Pact,
+    interaction_index_map: &mut InteractionIndexMap,
+) -> Result<(), Box<dyn Error>> {
+    let message = AsynchronousMessage {
+        description: description.to_string(),
+        contents: contents.clone(),
+        plugin_config: plugin_config(metadata),
+        ..Default::default()
+    };
+    pact.add_plugin(PROXY_PLUGIN, env!("CARGO_PKG_VERSION"), None)?;
+    let key = interaction_key(description, None);
+    add_interaction_and_amend_index(key, pact, interaction_index_map, &message)
+}
+
+fn plugin_config(metadata: HashMap<String, Value>) -> HashMap<String, HashMap<String, Value>> {
+    match metadata.is_empty() {
+        true => HashMap::new(),
+        false => HashMap::from([(PROXY_PLUGIN.to_string(), metadata)]),
+    }
+}
+
 fn add_interaction_and_amend_index(
-    pact_request: &HttpRequest,
+    key: InteractionKey,
     pact: &mut V4Pact,
     interaction_index_map: &mut InteractionIndexMap,
-    interaction: &SynchronousHttp,
+    interaction: &dyn Interaction,
 ) -> Result<(), Box<dyn Error>> {
     let new_item_index = pact.interactions.len() as u16;
     pact.add_interaction(interaction)?;
     interaction_index_map
         .get_mut(&(pact.consumer.name.clone(), pact.provider.name.clone()))
         .unwrap()
-        .insert(pact_request.path.clone(), new_item_index);
+        .entry(key)
+        .or_default()
+        .push(new_item_index);
     assert!(pact.interactions.len() as u16 == new_item_index + 1);
     Ok(())
 }
@@ -113,8 +181,8 @@ pub fn save_pact(pact: &V4Pact, pacts_folder: &Path) -> Result<(), Box<dyn Error
 #[cfg_attr(feature = "flame_it", flame)]
 pub fn get_consumer_provider(
     pact_request: &HttpRequest,
+    consumer_name: &str,
 ) -> Result<(String, String), Box<dyn Error>> {
-    let consumer_name = CONSUMER_NAME;
     let provider_url = Url::parse(&pact_request.path)?;
     let provider_name = provider_url.host_str().unwrap();
     Ok((consumer_name.to_string(), provider_name.to_string()))
@@ -123,7 +191,6 @@ pub fn get_consumer_provider(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pact_models::bodies::OptionalBody;
     use pact_models::v4::pact::V4Pact;
     use pact_models::{Consumer, Provider};
 
@@ -158,11 +225,26 @@ mod tests {
             HashMap::new(),
         );
         let _ = add_interaction_to_pact(
+            &pact_request.path,
+            None,
             &pact_request,
             &pact_response,
+            HashMap::new(),
             &mut pact,
             &mut interaction_index_map,
         );
         assert_eq!(pact.interactions().len(), 1);
     }
+
+    #[test]
+    fn test_interaction_keys_do_not_collide() {
+        assert_ne!(
+            interaction_key("/accounts given funded", None),
+            interaction_key("/accounts", Some("funded"))
+        );
+        assert_eq!(
+            interaction_key("/accounts", Some("funded")),
+            ("/accounts".to_string(), Some("funded".to_string()))
+        );
+    }
 }
@@ -0,0 +1,442 @@
+#[cfg(feature = "flame_it")]
+use flamer::flame;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use pact_models::bodies::OptionalBody;
+use pact_models::v4::http_parts::HttpResponse;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use tracing::debug;
+
+const CONTENT_ENCODING: &str = "content-encoding";
+
+/// Content codings the bodies are decoded from and encoded to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut decoded = vec![];
+        match self {
+            Encoding::Gzip => GzDecoder::new(bytes).read_to_end(&mut decoded)?,
+            Encoding::Deflate => DeflateDecoder::new(bytes).read_to_end(&mut decoded)?,
+            Encoding::Brotli => brotli::Decompressor::new(bytes, 4096).read_to_end(&mut decoded)?,
+        };
+        Ok(decoded)
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()?
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()?
+            }
+            Encoding::Brotli => {
+                let mut encoded = vec![];
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(bytes)?;
+                }
+                encoded
+            }
+        })
+    }
+
+    pub fn stream_decoder(&self) -> StreamDecoder {
+        match self {
+            Encoding::Gzip => StreamDecoder::Gzip(write::GzDecoder::new(vec![])),
+            Encoding::Deflate => StreamDecoder::Deflate(write::DeflateDecoder::new(vec![])),
+            Encoding::Brotli => StreamDecoder::Brotli(Box::new(
+                brotli::writer::DecompressorWriter::new(vec![], 4096),
+            )),
+        }
+    }
+
+    pub fn stream_encoder(&self) -> StreamEncoder {
+        match self {
+            Encoding::Gzip => StreamEncoder::Gzip(GzEncoder::new(vec![], Compression::default())),
+            Encoding::Deflate => {
+                StreamEncoder::Deflate(DeflateEncoder::new(vec![], Compression::default()))
+            }
+            Encoding::Brotli => {
+                StreamEncoder::Brotli(Box::new(brotli::CompressorWriter::new(vec![], 4096, 5, 22)))
+            }
+        }
+    }
+}
+
+/// Encodes a body sent chunk by chunk, the client can decode every chunk as it arrives
+pub enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl StreamEncoder {
+    /// Encoded bytes of the chunk, flushed so that nothing waits for the next chunk
+    pub fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+            StreamEncoder::Deflate(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+            StreamEncoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// The bytes ending the encoded body
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Gzip(encoder) => encoder.finish(),
+            StreamEncoder::Deflate(encoder) => encoder.finish(),
+            StreamEncoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// Decodes a body received chunk by chunk, so that the decoded length of every chunk is known
+pub enum StreamDecoder {
+    Gzip(write::GzDecoder<Vec<u8>>),
+    Deflate(write::DeflateDecoder<Vec<u8>>),
+    Brotli(Box<brotli::writer::DecompressorWriter<Vec<u8>>>),
+}
+
+impl StreamDecoder {
+    /// Decoded bytes of the chunk, as far as the chunk can be decoded on its own
+    pub fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            StreamDecoder::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.flush()?;
+                std::mem::take(decoder.get_mut())
+            }
+            StreamDecoder::Deflate(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.flush()?;
+                std::mem::take(decoder.get_mut())
+            }
+            StreamDecoder::Brotli(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.flush()?;
+                std::mem::take(decoder.get_mut())
+            }
+        })
+    }
+
+    /// The decoded bytes held back until the end of the body
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            StreamDecoder::Gzip(decoder) => decoder.finish(),
+            StreamDecoder::Deflate(decoder) => decoder.finish(),
+            StreamDecoder::Brotli(decoder) => Ok(decoder.into_inner().unwrap_or_else(|rest| rest)),
+        }
+    }
+}
+
+/// Codings of the body in the order they were applied, `None` when the body is not encoded
+/// or has an unknown coding, then `decode_body` keeps it as it is
+pub fn body_encodings(pact_response: &HttpResponse) -> Option<Vec<Encoding>> {
+    let codings = header_value(pact_response, CONTENT_ENCODING)?;
+    let mut encodings = vec![];
+    for coding in codings
+        .split(',')
+        .filter(|coding| !coding.trim().is_empty())
+    {
+        if coding.trim().eq_ignore_ascii_case("identity") {
+            continue;
+        }
+        match Encoding::from_name(coding) {
+            Some(encoding) => encodings.push(encoding),
+            None => {
+                debug!("Unknown content encoding {}, body is kept encoded", coding);
+                return None;
+            }
+        }
+    }
+    Some(encodings)
+}
+
+/// Decodes the body of a compressed upstream response, so that the pact is readable.
+/// Bodies with an unknown coding are kept as they are
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn decode_body(pact_response: &mut HttpResponse) -> Result<(), Box<dyn Error>> {
+    let encodings = match body_encodings(pact_response) {
+        Some(encodings) => encodings,
+        None => return Ok(()),
+    };
+    if let OptionalBody::Present(body, content_type, content_type_hint) = &pact_response.body {
+        let mut decoded = body.to_vec();
+        // the codings are listed in the order they were applied
+        for encoding in encodings.iter().rev() {
+            decoded = encoding.decode(&decoded)?;
+        }
+        pact_response.body =
+            OptionalBody::Present(decoded.into(), content_type.clone(), *content_type_hint);
+    }
+    remove_header(pact_response, CONTENT_ENCODING);
+    set_content_length(pact_response);
+    Ok(())
+}
+
+/// Encodes the body with the preferred coding of the client's `Accept-Encoding`
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn encode_body(
+    pact_response: &mut HttpResponse,
+    accept_encoding: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let encoding = match response_encoding(pact_response, accept_encoding) {
+        Some(encoding) => encoding,
+        None => return Ok(()),
+    };
+    if let OptionalBody::Present(body, content_type, content_type_hint) = &pact_response.body {
+        let encoded = encoding.encode(body)?;
+        pact_response.body =
+            OptionalBody::Present(encoded.into(), content_type.clone(), *content_type_hint);
+        pact_response
+            .headers
+            .get_or_insert_with(Default::default)
+            .insert(
+                CONTENT_ENCODING.to_string(),
+                vec![encoding.name().to_string()],
+            );
+        set_content_length(pact_response);
+    }
+    Ok(())
+}
+
+/// Coding the body is sent to the client in, `None` for a body that is already encoded
+pub fn response_encoding(
+    pact_response: &HttpResponse,
+    accept_encoding: Option<&str>,
+) -> Option<Encoding> {
+    if header_value(pact_response, CONTENT_ENCODING).is_some() {
+        return None;
+    }
+    accept_encoding.and_then(preferred_encoding)
+}
+
+/// Supported coding with the highest quality, the first listed one on a tie
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut preferred = None;
+    // the codings with a quality of 0 are not acceptable
+    let mut best_quality = 0.0;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let encoding = match Encoding::from_name(parts.next().unwrap_or_default()) {
+            Some(encoding) => encoding,
+            None => continue,
+        };
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality > best_quality {
+            preferred = Some(encoding);
+            best_quality = quality;
+        }
+    }
+    preferred
+}
+
+fn header_value(pact_response: &HttpResponse, name: &str) -> Option<String> {
+    pact_response
+        .headers
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, values)| values.join(","))
+}
+
+fn remove_header(pact_response: &mut HttpResponse, name: &str) {
+    if let Some(headers) = pact_response.headers.as_mut() {
+        headers.retain(|key, _| !key.eq_ignore_ascii_case(name));
+    }
+}
+
+fn set_content_length(pact_response: &mut HttpResponse) {
+    let content_length = match &pact_response.body {
+        OptionalBody::Present(body, ..) => body.len(),
+        _ => 0,
+    };
+    if let Some(headers) = pact_response.headers.as_mut() {
+        for (key, values) in headers.iter_mut() {
+            if key.eq_ignore_ascii_case("content-length") {
+                *values = vec![content_length.to_string()];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use std::collections::HashMap;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_decode_and_encode_body() {
+        let body = r#"{"key":"value"}"#;
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let encoded = encoding.encode(body.as_bytes()).unwrap();
+            let mut pact_response = HttpResponse {
+                headers: Some(HashMap::from([
+                    (
+                        "content-encoding".to_string(),
+                        vec![encoding.name().to_string()],
+                    ),
+                    (
+                        "content-length".to_string(),
+                        vec![encoded.len().to_string()],
+                    ),
+                ])),
+                body: OptionalBody::Present(encoded.into(), None, None),
+                ..Default::default()
+            };
+            decode_body(&mut pact_response).unwrap();
+            assert_eq!(pact_response.body.value().unwrap(), body.as_bytes());
+            let headers = pact_response.headers.as_ref().unwrap();
+            assert!(!headers.contains_key("content-encoding"));
+            assert_eq!(headers["content-length"], vec![body.len().to_string()]);
+
+            encode_body(&mut pact_response, Some(encoding.name())).unwrap();
+            let headers = pact_response.headers.as_ref().unwrap();
+            assert_eq!(headers["content-encoding"], vec![encoding.name()]);
+            let encoded = pact_response.body.value().unwrap();
+            assert_eq!(headers["content-length"], vec![encoded.len().to_string()]);
+            assert_eq!(encoding.decode(&encoded).unwrap(), body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_stream_encoder() {
+        let chunks = ["data: 1\n\n", "data: 2\n\n"];
+        for encoding in [Encoding::Gzip, Encoding::Deflate, Encoding::Brotli] {
+            let mut encoder = encoding.stream_encoder();
+            let mut encoded = vec![];
+            for chunk in chunks {
+                let encoded_chunk = encoder.encode(chunk.as_bytes()).unwrap();
+                // flushed, not held back until the end of the stream
+                assert!(!encoded_chunk.is_empty());
+                encoded.extend(encoded_chunk);
+            }
+            encoded.extend(encoder.finish().unwrap());
+            assert_eq!(
+                encoding.decode(&encoded).unwrap(),
+                chunks.concat().as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_preferred_encoding() {
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, br;q=0.9"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(preferred_encoding("gzip;q=0, identity"), None);
+        assert_eq!(preferred_encoding("zstd"), None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_content_encoding() {
+        const BODY: &str = r#"{"key":"value"}"#;
+        let upstream = TestUpstream::start(|_| {
+            Response::from_data(Encoding::Gzip.encode(BODY.as_bytes()).unwrap())
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .unwrap(),
+                )
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Encoding"[..], &b"gzip"[..]).unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/data",
+            upstream.port
+        );
+        let client = reqwest::Client::builder()
+            .no_gzip()
+            .no_deflate()
+            .build()
+            .unwrap();
+        for (accept_encoding, encoding) in [
+            ("gzip", Some(Encoding::Gzip)),
+            ("br;q=1, gzip;q=0.5", Some(Encoding::Brotli)),
+            ("identity", None),
+        ] {
+            let response = client
+                .get(url.as_str())
+                .header("Accept-Encoding", accept_encoding)
+                .send()
+                .await
+                .unwrap();
+            let content_encoding = response
+                .headers()
+                .get("content-encoding")
+                .map(|value| value.to_str().unwrap().to_string());
+            assert_eq!(content_encoding.as_deref(), encoding.map(|e| e.name()));
+            let body = response.bytes().await.unwrap();
+            let body = match encoding {
+                Some(encoding) => encoding.decode(&body).unwrap(),
+                None => body.to_vec(),
+            };
+            assert_eq!(body, BODY.as_bytes());
+        }
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+        // the pact is readable
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.replace(' ', "").contains(r#""key":"value""#));
+        assert!(!pact.contains("content-encoding"));
+    }
+}
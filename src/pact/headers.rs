@@ -0,0 +1,288 @@
+use pact_models::v4::http_parts::HttpRequest;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Headers that only apply to a single connection, RFC 7230 section 6.1
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Value recorded instead of a redacted header
+pub const REDACTED: &str = "<redacted>";
+/// Header naming the provider state a request is recorded in and replayed from
+pub const PROVIDER_STATE_HEADER: &str = "x-pact-provider-state";
+
+/// Whether the header must not be passed on by a proxy, `connection_listed` are the
+/// header names in the `Connection` header of the message
+pub fn is_hop_by_hop(name: &str, connection_listed: &[String]) -> bool {
+    let name = name.to_lowercase();
+    HOP_BY_HOP_HEADERS.contains(&name.as_str()) || connection_listed.contains(&name)
+}
+
+/// Lowercased header names listed in the `Connection` header
+pub fn connection_listed(headers: &HashMap<String, Vec<String>>) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, values)| values.iter())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Removes the hop-by-hop headers and the headers listed in `Connection`
+pub fn strip_hop_by_hop(headers: &mut HashMap<String, Vec<String>>) {
+    let connection_listed = connection_listed(headers);
+    headers.retain(|key, _| !is_hop_by_hop(key, &connection_listed));
+}
+
+/// Appends this proxy to the `Via` header, e.g. `1.1 pact-proxy-rs`
+pub fn add_via(headers: &mut HashMap<String, Vec<String>>, pseudonym: &str) {
+    let via = "1.1 ".to_owned() + pseudonym;
+    match headers
+        .iter_mut()
+        .find(|(key, _)| key.eq_ignore_ascii_case("via"))
+    {
+        Some((_, values)) => values.push(via),
+        None => {
+            headers.insert("via".to_string(), vec![via]);
+        }
+    }
+}
+
+/// Sets `X-Forwarded-For`, `X-Forwarded-Host` and `X-Forwarded-Proto` from the request
+/// received by the proxy. `host` is the `Host` header sent by the client
+pub fn add_forwarded_headers(
+    pact_request: &mut HttpRequest,
+    client_addr: Option<SocketAddr>,
+    host: Option<&str>,
+    proto: &str,
+) {
+    let headers = pact_request.headers.get_or_insert_with(HashMap::new);
+    if let Some(client_addr) = client_addr {
+        let client_ip = client_addr.ip().to_string();
+        match headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case("x-forwarded-for"))
+        {
+            Some((_, values)) => {
+                let forwarded_for = values.join(", ") + ", " + client_ip.as_str();
+                *values = vec![forwarded_for];
+            }
+            None => {
+                headers.insert("x-forwarded-for".to_string(), vec![client_ip]);
+            }
+        }
+    }
+    if let Some(host) = host {
+        headers.insert("x-forwarded-host".to_string(), vec![host.to_string()]);
+    }
+    headers.insert("x-forwarded-proto".to_string(), vec![proto.to_string()]);
+}
+
+/// Replaces the values of the named headers, e.g. credentials that must not end up in the pacts
+pub fn redact_headers(headers: &mut HashMap<String, Vec<String>>, redacted: &[String]) {
+    for (name, values) in headers.iter_mut() {
+        if redacted
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(name))
+        {
+            *values = vec![REDACTED.to_string()];
+        }
+    }
+}
+
+/// Value of a header of the proxy and the request without it, the header is neither
+/// forwarded nor recorded
+pub fn split_header<'a>(
+    pact_request: &'a HttpRequest,
+    name: &str,
+) -> (Option<String>, Cow<'a, HttpRequest>) {
+    let value = match pact_request
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(name))
+        .and_then(|values| values.first())
+    {
+        Some(value) => value.clone(),
+        None => return (None, Cow::Borrowed(pact_request)),
+    };
+    let mut pact_request = pact_request.clone();
+    if let Some(headers) = &mut pact_request.headers {
+        headers.remove(name);
+    }
+    (Some(value), Cow::Owned(pact_request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::ListenAddr;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use crate::tls::TlsCertificate;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HashMap::from([
+            (
+                "Connection".to_string(),
+                vec!["keep-alive, X-Trace".to_string()],
+            ),
+            ("Keep-Alive".to_string(), vec!["timeout=5".to_string()]),
+            ("x-trace".to_string(), vec!["1".to_string()]),
+            ("transfer-encoding".to_string(), vec!["chunked".to_string()]),
+            (
+                "Proxy-Authorization".to_string(),
+                vec!["Basic abc".to_string()],
+            ),
+            ("accept".to_string(), vec!["*/*".to_string()]),
+        ]);
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.keys().collect::<Vec<_>>(), vec!["accept"]);
+    }
+
+    #[test]
+    fn test_add_forwarded_headers() {
+        let mut pact_request = HttpRequest {
+            headers: Some(HashMap::from([(
+                "X-Forwarded-For".to_string(),
+                vec!["10.0.0.1".to_string()],
+            )])),
+            ..Default::default()
+        };
+        add_forwarded_headers(
+            &mut pact_request,
+            Some("127.0.0.1:50000".parse().unwrap()),
+            Some("localhost:8080"),
+            "http",
+        );
+        let headers = pact_request.headers.unwrap();
+        assert_eq!(headers["X-Forwarded-For"], vec!["10.0.0.1, 127.0.0.1"]);
+        assert_eq!(headers["x-forwarded-host"], vec!["localhost:8080"]);
+        assert_eq!(headers["x-forwarded-proto"], vec!["http"]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_hop_by_hop_headers() {
+        let upstream = TestUpstream::start(|request| {
+            let mut names = request
+                .headers()
+                .iter()
+                .map(|header| header.field.as_str().as_str().to_lowercase())
+                .collect::<Vec<_>>();
+            names.sort();
+            let via = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Via"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            Response::from_string(names.join(",") + "\n" + via.as_str())
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Keep-Alive"[..], &b"timeout=5"[..]).unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        pact_server.add_via_header("pact-proxy-rs");
+        pact_server.add_forwarded_headers();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let response = reqwest::Client::new()
+            .get(format!(
+                "http://localhost:{port}/http/127.0.0.1:{}/headers",
+                upstream.port
+            ))
+            .header("Connection", "X-Secret")
+            .header("X-Secret", "1")
+            .header("Proxy-Authorization", "Basic abc")
+            .header("Keep-Alive", "timeout=5")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["via"], "1.1 pact-proxy-rs");
+        assert!(response.headers().get("keep-alive").is_none());
+        let body = response.text().await.unwrap();
+        let (names, via) = body.split_once('\n').unwrap();
+        let names = names.split(',').collect::<Vec<_>>();
+        for hop_by_hop in ["x-secret", "proxy-authorization", "keep-alive"] {
+            assert!(!names.contains(&hop_by_hop), "{hop_by_hop} was forwarded");
+        }
+        for forwarded in ["x-forwarded-for", "x-forwarded-host", "x-forwarded-proto"] {
+            assert!(names.contains(&forwarded), "{forwarded} is missing");
+        }
+        assert_eq!(via, "1.1 pact-proxy-rs");
+        pact_server.stop().unwrap();
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        for hop_by_hop in [
+            "proxy-authorization",
+            "x-secret",
+            "keep-alive",
+            "\"connection\"",
+        ] {
+            assert!(!pact.contains(hop_by_hop), "{hop_by_hop} was recorded");
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_forwarded_client() {
+        let upstream = TestUpstream::start(|request| {
+            let mut forwarded = [String::new(), String::new()];
+            for header in request.headers() {
+                if header.field.equiv("X-Forwarded-For") {
+                    forwarded[0] = header.value.to_string();
+                } else if header.field.equiv("X-Forwarded-Proto") {
+                    forwarded[1] = header.value.to_string();
+                }
+            }
+            Response::from_string(forwarded.join(" ")).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let certificate = TlsCertificate::SelfSigned(pacts_folder.with_extension("tls"));
+        let addr = ListenAddr::from_str("[::1]:0").unwrap();
+        // the front passes the connections through from 127.0.0.1, not from the client
+        for (proto, tls) in [("http", false), ("https", true)] {
+            let mut pact_server = PactServer::listen(&pacts_folder, None, &addr, None).unwrap();
+            pact_server.add_forwarded_headers();
+            if tls {
+                pact_server.serve_tls(&certificate).unwrap();
+            }
+            let port = pact_server.port().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            // the certificate is for localhost, which only resolves to the ipv6 front
+            let front_addr = SocketAddr::new("::1".parse().unwrap(), port.parse().unwrap());
+            let mut client = reqwest::Client::builder().resolve("localhost", front_addr);
+            if tls {
+                let pem = std::fs::read(certificate.cert_path()).unwrap();
+                client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem).unwrap());
+            }
+            let response = client
+                .build()
+                .unwrap()
+                .get(format!(
+                    "{proto}://localhost:{port}/http/127.0.0.1:{}/{proto}",
+                    upstream.port
+                ))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), format!("::1 {proto}"));
+            pact_server.stop().unwrap();
+        }
+    }
+}
@@ -1,5 +1,11 @@
+pub mod body_files;
+pub mod chunks;
 pub mod edit;
+pub mod encoding;
+pub mod headers;
 pub mod pact_to_request;
 pub mod pact_to_response;
 pub mod request_to_pact;
 pub mod response_to_pact;
+pub mod rewrite;
+pub mod session;
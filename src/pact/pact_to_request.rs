@@ -1,3 +1,4 @@
+use crate::pact::headers::{connection_listed, is_hop_by_hop};
 use pact_models::v4::http_parts::HttpRequest;
 use reqwest::RequestBuilder;
 use std::error::Error;
@@ -7,7 +8,13 @@ pub fn copy_pact_headers_to_request(
     mut reqwest_request: RequestBuilder,
 ) -> Result<RequestBuilder, Box<dyn Error>> {
     for hash_map in pact_request.headers.iter() {
+        let connection_listed = connection_listed(hash_map);
         for (key, values) in hash_map {
+            // the content length of the forwarded body is set by the client
+            if is_hop_by_hop(key, &connection_listed) || key.eq_ignore_ascii_case("content-length")
+            {
+                continue;
+            }
             for value in values {
                 reqwest_request = reqwest_request.header(key, value);
             }
@@ -1,19 +1,36 @@
+use crate::pact::chunks::Chunk;
+use crate::pact::encoding::{encode_body, response_encoding, Encoding, StreamEncoder};
+use crate::pact::headers::strip_hop_by_hop;
 use bytes::Bytes;
 #[cfg(feature = "flame_it")]
 use flamer::flame;
 use pact_models::bodies::OptionalBody;
 use pact_models::v4::http_parts::HttpResponse;
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::Cursor;
-use tiny_http::Header;
+use std::fs::File;
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
 use tiny_http::Response;
+use tiny_http::{Header, StatusCode};
 use tracing::debug;
 
 #[cfg_attr(feature = "flame_it", flame)]
 pub fn pact_response_to_http_response(
     pact_response: &HttpResponse,
+    accept_encoding: Option<&str>,
 ) -> Result<Response<Cursor<Vec<u8>>>, Box<dyn Error>> {
     debug!("pact_response: {:?}", pact_response);
+    // the pacts store decoded bodies, the client gets them in the coding it accepts
+    let mut pact_response = pact_response.clone();
+    // recorded before the hop-by-hop headers were stripped, e.g. transfer-encoding: chunked
+    if let Some(headers) = pact_response.headers.as_mut() {
+        strip_hop_by_hop(headers);
+    }
+    encode_body(&mut pact_response, accept_encoding)?;
     let body_bytes = match pact_response.body {
         OptionalBody::Present(ref body, ..) => body.clone(),
         OptionalBody::Empty => Bytes::new(),
@@ -22,7 +39,192 @@ pub fn pact_response_to_http_response(
     };
 
     debug!("json body: {:?}", body_bytes);
-    // to vec u8
+    let response = Response::new(
+        tiny_http::StatusCode(pact_response.status),
+        http_headers(&pact_response),
+        Cursor::new(body_bytes.into()),
+        None,
+        None,
+    );
+    Ok(response)
+}
+
+/// Response streaming a body spilled over to a file, the body is sent as it was recorded.
+/// The bodies to rewrite or encode are read from their files instead, see `read_body_file`
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn pact_response_to_streamed_http_response(
+    pact_response: &HttpResponse,
+    body_file: &Path,
+) -> Result<Response<File>, Box<dyn Error>> {
+    debug!(
+        "pact_response: {:?}, body file: {:?}",
+        pact_response, body_file
+    );
+    let mut pact_response = pact_response.clone();
+    if let Some(headers) = pact_response.headers.as_mut() {
+        strip_hop_by_hop(headers);
+        // the length is the one of the file
+        headers.retain(|key, _| !key.eq_ignore_ascii_case("content-length"));
+    }
+    let file = File::open(body_file)?;
+    let length = file.metadata()?.len() as usize;
+    Ok(Response::new(
+        tiny_http::StatusCode(pact_response.status),
+        http_headers(&pact_response),
+        file,
+        Some(length),
+        None,
+    ))
+}
+
+/// Streaming response sent chunk by chunk, a recorded stream with the timing it was
+/// recorded with, a stream of the upstream as its chunks arrive
+pub struct PacedResponse {
+    pact_response: HttpResponse,
+    source: ChunkSource,
+    /// Coding the client accepts, the recorded bodies are stored decoded
+    encoding: Option<Encoding>,
+    /// Response to a HEAD request, only the headers are sent
+    head_only: bool,
+}
+
+enum ChunkSource {
+    /// `speed_up` divides the delays
+    Recorded {
+        chunks: Vec<Chunk>,
+        speed_up: f64,
+    },
+    Relayed(Receiver<Bytes>),
+}
+
+impl PacedResponse {
+    pub fn new(
+        pact_response: &HttpResponse,
+        chunks: Vec<Chunk>,
+        speed_up: Option<f64>,
+        accept_encoding: Option<&str>,
+        head_only: bool,
+    ) -> Self {
+        let mut pact_response = chunked_head(pact_response);
+        let encoding = response_encoding(&pact_response, accept_encoding);
+        if let Some(encoding) = encoding {
+            pact_response
+                .headers
+                .get_or_insert_with(HashMap::new)
+                .insert(
+                    "content-encoding".to_string(),
+                    vec![encoding.name().to_string()],
+                );
+        }
+        PacedResponse {
+            pact_response,
+            source: ChunkSource::Recorded {
+                chunks,
+                speed_up: speed_up.unwrap_or(1.0),
+            },
+            encoding,
+            head_only,
+        }
+    }
+
+    /// Sends the chunks of the upstream as they are received, in the coding of the upstream
+    pub fn relay(pact_response: &HttpResponse, chunks: Receiver<Bytes>, head_only: bool) -> Self {
+        PacedResponse {
+            pact_response: chunked_head(pact_response),
+            source: ChunkSource::Relayed(chunks),
+            encoding: None,
+            head_only,
+        }
+    }
+
+    /// Writes the whole http/1.1 response with a chunked body, a chunk is flushed when
+    /// its time has come. The bytes after the recorded chunks, e.g. of a rewritten body,
+    /// are sent with the last one
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let status = StatusCode(self.pact_response.status);
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            status.0,
+            status.default_reason_phrase()
+        )?;
+        for header in http_headers(&self.pact_response) {
+            write!(writer, "{}: {}\r\n", header.field, header.value)?;
+        }
+        writer.write_all(b"Transfer-Encoding: chunked\r\n\r\n")?;
+        if self.head_only {
+            return writer.flush();
+        }
+        writer.flush()?;
+        let mut encoder = self.encoding.map(|encoding| encoding.stream_encoder());
+        match &self.source {
+            ChunkSource::Recorded { chunks, speed_up } => {
+                let body = self.pact_response.body.value().unwrap_or_default();
+                let started = Instant::now();
+                let mut position = 0;
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let end = match i == chunks.len() - 1 {
+                        true => body.len(),
+                        false => (position + chunk.length).min(body.len()),
+                    };
+                    let due = match *speed_up > 0.0 {
+                        true => chunk.offset.div_f64(*speed_up),
+                        false => Duration::ZERO,
+                    };
+                    thread::sleep(due.saturating_sub(started.elapsed()));
+                    write_encoded_chunk(writer, &mut encoder, &body[position..end])?;
+                    position = end;
+                }
+                write_encoded_chunk(writer, &mut encoder, &body[position..])?;
+            }
+            ChunkSource::Relayed(chunks) => {
+                for chunk in chunks.iter() {
+                    write_encoded_chunk(writer, &mut encoder, &chunk)?;
+                }
+            }
+        }
+        if let Some(encoder) = encoder {
+            write_chunk(writer, &encoder.finish()?)?;
+        }
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()
+    }
+}
+
+/// Headers of a response with a chunked body
+fn chunked_head(pact_response: &HttpResponse) -> HttpResponse {
+    let mut pact_response = pact_response.clone();
+    if let Some(headers) = pact_response.headers.as_mut() {
+        strip_hop_by_hop(headers);
+        headers.retain(|key, _| !key.eq_ignore_ascii_case("content-length"));
+    }
+    pact_response
+}
+
+fn write_encoded_chunk<W: Write>(
+    writer: &mut W,
+    encoder: &mut Option<StreamEncoder>,
+    data: &[u8],
+) -> io::Result<()> {
+    match encoder {
+        Some(encoder) if !data.is_empty() => write_chunk(writer, &encoder.encode(data)?),
+        _ => write_chunk(writer, data),
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    // an empty chunk would end the body
+    if data.is_empty() {
+        return Ok(());
+    }
+    write!(writer, "{:x}\r\n", data.len())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+fn http_headers(pact_response: &HttpResponse) -> Vec<Header> {
     let mut headers = vec![];
     for hashmap in pact_response.headers.iter() {
         for (key, values) in hashmap {
@@ -31,15 +233,7 @@ pub fn pact_response_to_http_response(
             }
         }
     }
-
-    let response = Response::new(
-        tiny_http::StatusCode(pact_response.status),
-        headers,
-        Cursor::new(body_bytes.into()),
-        None,
-        None,
-    );
-    Ok(response)
+    headers
 }
 
 #[cfg(test)]
@@ -61,9 +255,62 @@ mod tests {
             body: OptionalBody::Present(test_json_body.as_bytes().to_vec().into(), None, None),
             ..Default::default()
         };
-        let http_response = pact_response_to_http_response(&pact_response);
+        let http_response = pact_response_to_http_response(&pact_response, None);
         assert!(http_response.is_ok());
         assert!(http_response.as_ref().unwrap().status_code() == 200);
         assert!(http_response.as_ref().unwrap().headers().len() == 1);
     }
+
+    #[test]
+    fn test_paced_response_encoding_and_head() {
+        let body = "data: 1\n\ndata: 2\n\n";
+        let pact_response = HttpResponse {
+            status: 200,
+            headers: Some(HashMap::from([(
+                "content-type".to_string(),
+                vec!["text/event-stream".to_string()],
+            )])),
+            body: OptionalBody::Present(body.into(), None, None),
+            ..Default::default()
+        };
+        let chunks = vec![
+            Chunk {
+                offset: Duration::ZERO,
+                length: 9,
+            },
+            Chunk {
+                offset: Duration::ZERO,
+                length: 9,
+            },
+        ];
+        let write = |accept_encoding, head_only| {
+            let response = PacedResponse::new(
+                &pact_response,
+                chunks.clone(),
+                None,
+                accept_encoding,
+                head_only,
+            );
+            let mut written = vec![];
+            response.write_to(&mut written).unwrap();
+            let end_of_head = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let head = String::from_utf8(written[..end_of_head].to_vec()).unwrap();
+            (head, written[end_of_head..].to_vec())
+        };
+
+        let (head, mut chunked) = write(Some("gzip"), false);
+        assert!(head.contains("content-encoding: gzip"));
+        let mut encoded = vec![];
+        while let Some(line_end) = chunked.windows(2).position(|w| w == b"\r\n") {
+            let size = std::str::from_utf8(&chunked[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            encoded.extend(&chunked[line_end + 2..line_end + 2 + size]);
+            chunked.drain(..line_end + 2 + size + 2);
+        }
+        assert_eq!(Encoding::Gzip.decode(&encoded).unwrap(), body.as_bytes());
+
+        let (head, rest) = write(Some("gzip"), true);
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(rest.is_empty());
+    }
 }
@@ -1,35 +1,65 @@
+use crate::pact::headers::strip_hop_by_hop;
+use crate::pact::session;
+use crate::routing::Routing;
 #[cfg(feature = "flame_it")]
 use flamer::flame;
 use pact_models::bodies::OptionalBody;
+use pact_models::content_types::ContentType;
 use pact_models::v4::http_parts::HttpRequest;
 use std::collections::HashMap;
 use std::error::Error;
-use tiny_http::Request;
+use std::io;
+use tiny_http::{Header, Method, Request};
 use tracing::debug;
 use url::Url;
 
 #[cfg_attr(feature = "flame_it", flame)]
-pub fn http_request_to_pact(request: &mut Request) -> Result<HttpRequest, Box<dyn Error>> {
-    // we assume a post request and that all the data is in the body and that from_json will just read it
+pub fn http_request_to_pact(
+    request: &mut Request,
+    routing: &Routing,
+) -> Result<HttpRequest, Box<dyn Error>> {
     let mut pact_request = HttpRequest::default();
-    let url = get_forward_url(request);
+    let (session, target) = match session::strip_session_prefix(request.url()) {
+        Some((session, target)) => (Some(session.to_string()), target.to_string()),
+        None => (None, request.url().to_string()),
+    };
+    let url = routing.forward_url(&target, request.headers())?;
 
-    set_method(request, &mut pact_request)?;
+    set_method(request.method(), &mut pact_request);
     set_path(&mut pact_request, &url);
     set_query(&mut pact_request, &url);
-    set_headers(request, &mut pact_request, url)?;
-    set_body(request);
+    set_headers(request.headers(), &mut pact_request, url)?;
+    // the session of the path prefix is passed on like the one of the header
+    if let Some(session) = session {
+        session::add_session_header(&mut pact_request, &session);
+    }
+    set_body(request, &mut pact_request)?;
     debug!("pact_request: {:?}", pact_request);
     Ok(pact_request)
 }
 
-fn set_method(request: &mut Request, pact_request: &mut HttpRequest) -> Result<(), Box<dyn Error>> {
-    if request.method() != &tiny_http::Method::Get {
-        debug!("request: {:?}", request);
-        return Err("Only GET requests are supported".into());
-    }
-    pact_request.method = request.method().to_string();
-    Ok(())
+/// Converts a request read from an intercepted https tunnel,
+/// the url is already resolved from the tunnel authority
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn tunneled_request_to_pact(
+    method: &Method,
+    url: Url,
+    headers: &[Header],
+    body: Vec<u8>,
+) -> Result<HttpRequest, Box<dyn Error>> {
+    let mut pact_request = HttpRequest::default();
+    set_method(method, &mut pact_request);
+    set_path(&mut pact_request, &url);
+    set_query(&mut pact_request, &url);
+    set_headers(headers, &mut pact_request, url)?;
+    set_body_content(body, headers, &mut pact_request);
+    debug!("pact_request: {:?}", pact_request);
+    Ok(pact_request)
+}
+
+fn set_method(method: &Method, pact_request: &mut HttpRequest) {
+    debug!("method: {:?}", method);
+    pact_request.method = method.to_string();
 }
 
 fn set_path(pact_request: &mut HttpRequest, url: &Url) {
@@ -37,32 +67,44 @@ fn set_path(pact_request: &mut HttpRequest, url: &Url) {
     pact_request.path = url.to_string();
 }
 
-fn set_body(request: &mut Request) {
-    let mut content = String::new();
+fn set_body(request: &mut Request, pact_request: &mut HttpRequest) -> io::Result<()> {
+    let mut content = Vec::new();
     debug!("reading request body");
-    request.as_reader().read_to_string(&mut content).unwrap();
-    debug!("request body: {}", content);
-    match content.is_empty() {
+    request.as_reader().read_to_end(&mut content)?;
+    set_body_content(content, request.headers(), pact_request);
+    Ok(())
+}
+
+fn set_body_content(content: Vec<u8>, headers: &[Header], pact_request: &mut HttpRequest) {
+    debug!("request body: {:?}", content);
+    let content_type = headers
+        .iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .and_then(|header| ContentType::parse(header.value.as_str()).ok());
+    pact_request.body = match content.is_empty() {
         true => OptionalBody::Empty,
-        false => OptionalBody::Present(content.into_bytes().into(), None, None),
+        false => OptionalBody::Present(content.into(), content_type, None),
     };
 }
 
 fn set_headers(
-    request: &mut Request,
+    headers: &[Header],
     pact_request: &mut HttpRequest,
     url: Url,
 ) -> Result<(), Box<dyn Error>> {
-    pact_request.headers = match request.headers().is_empty() {
+    pact_request.headers = match headers.is_empty() {
         true => None,
         false => {
+            // header names are case insensitive, the repeated ones keep their order
             let mut headers_map: HashMap<String, Vec<String>> = HashMap::new();
-            for header in request.headers().iter() {
+            for header in headers.iter() {
                 headers_map
-                    .entry(header.field.to_string())
-                    .or_insert(vec![header.value.to_string()])
+                    .entry(header.field.as_str().as_str().to_lowercase())
+                    .or_default()
                     .push(header.value.to_string());
             }
+            // neither forwarded nor recorded, e.g. the Proxy-Authorization credentials
+            strip_hop_by_hop(&mut headers_map);
             Some(headers_map)
         }
     };
@@ -71,14 +113,14 @@ fn set_headers(
 }
 
 fn set_host_header(pact_request: &mut HttpRequest, url: Url) -> Result<(), Box<dyn Error>> {
+    let mut upstream_host = url.host().ok_or("url should have host")?.to_string();
+    // the port is only left out of the url when it is the default one for the scheme
+    if let Some(port) = url.port() {
+        upstream_host = upstream_host + ":" + port.to_string().as_str();
+    }
     if let Some(headers) = &mut pact_request.headers {
         if let Some(host) = headers.get_mut("host") {
-            host[0] = url.host().ok_or("url should have host")?.to_string();
-            host.truncate(1);
-        }
-        if let Some(host) = headers.get_mut("Host") {
-            host[0] = url.host().ok_or("url should have host")?.to_string();
-            host.truncate(1);
+            *host = vec![upstream_host];
         }
     }
     Ok(())
@@ -87,10 +129,7 @@ fn set_host_header(pact_request: &mut HttpRequest, url: Url) -> Result<(), Box<d
 fn set_query(pact_request: &mut HttpRequest, url: &Url) {
     let mut query_map: HashMap<String, Vec<String>> = HashMap::new();
     for (key, value) in url.query_pairs() {
-        query_map
-            .entry(key.into())
-            .or_insert(vec![value.clone().into()])
-            .push(value.clone().into());
+        query_map.entry(key.into()).or_default().push(value.into());
     }
     pact_request.query = match query_map.is_empty() {
         true => None,
@@ -98,20 +137,88 @@ fn set_query(pact_request: &mut HttpRequest, url: &Url) {
     };
 }
 
-fn get_forward_url(request: &mut Request) -> Url {
-    let relative_url = request.url();
-    // remove / from the beginning of the url if it exists
-    // strip_prefix
-    let relative_url = match relative_url.strip_prefix('/') {
-        Some(url) => url,
-        None => relative_url,
-    };
-    let scheme = relative_url.split('/').next().unwrap().to_string();
-    let host = relative_url.split('/').nth(1).unwrap().to_string();
-    let path = relative_url
-        .split('/')
-        .skip(2)
-        .collect::<Vec<&str>>()
-        .join("/");
-    Url::parse(&(scheme + "://" + host.as_str() + "/" + path.as_str())).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{record_then_replay, test_pacts_folder, TestUpstream};
+    use tiny_http::Response;
+
+    #[test]
+    fn test_tunneled_request_to_pact_repeated_headers_and_query() {
+        let header = |field: &str, value: &str| {
+            Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+        };
+        let headers = [
+            header("Host", "localhost:8080"),
+            header("X-Multi", "1"),
+            header("Accept", "text/plain, application/json"),
+            header("x-multi", "2"),
+        ];
+        let url = Url::parse("https://api.example.com/items?a=1&b=2&a=3").unwrap();
+        let pact_request = tunneled_request_to_pact(&Method::Get, url, &headers, vec![]).unwrap();
+        let expected_headers = HashMap::from([
+            ("host".to_string(), vec!["api.example.com".to_string()]),
+            (
+                "x-multi".to_string(),
+                vec!["1".to_string(), "2".to_string()],
+            ),
+            (
+                "accept".to_string(),
+                vec!["text/plain, application/json".to_string()],
+            ),
+        ]);
+        assert_eq!(pact_request.headers, Some(expected_headers));
+        let expected_query = HashMap::from([
+            ("a".to_string(), vec!["1".to_string(), "3".to_string()]),
+            ("b".to_string(), vec!["2".to_string()]),
+        ]);
+        assert_eq!(pact_request.query, Some(expected_query));
+        // the recorded request round trips through the pact file
+        let round_trip = HttpRequest::from_json(&pact_request.to_json()).unwrap();
+        assert_eq!(round_trip.headers, pact_request.headers);
+        assert_eq!(round_trip.query, pact_request.query);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_request_bodies() {
+        const BINARY: &[u8] = b"\x00\xff\xc3\x28 not utf-8";
+        let upstream = TestUpstream::start(|request| {
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            let mut response = format!("{} ", request.method()).into_bytes();
+            response.extend(body);
+            Response::from_data(response).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let upstream = &upstream;
+        record_then_replay(
+            &pacts_folder,
+            |builder| builder,
+            |port| async move {
+                let url = upstream.url(&port, "/orders");
+                let client = reqwest::Client::new();
+                for (content_type, body) in [
+                    ("application/json", &br#"{"qty":1}"#[..]),
+                    ("application/json", br#"{"qty":2}"#),
+                    ("application/octet-stream", BINARY),
+                    ("text/plain", b""),
+                ] {
+                    let response = client
+                        .post(url.as_str())
+                        .header("content-type", content_type)
+                        .body(body)
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(response.bytes().await.unwrap(), [b"POST ", body].concat());
+                }
+                let response = client.get(url.as_str()).send().await.unwrap();
+                assert_eq!(response.text().await.unwrap(), "GET ");
+            },
+        )
+        .await;
+        assert_eq!(upstream.hits(), 5);
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains(r#""qty": 2"#));
+    }
 }
@@ -1,3 +1,8 @@
+use crate::pact::chunks::is_streaming;
+use bytes::Bytes;
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::{detect_content_type_from_bytes, ContentTypeHint};
+use pact_models::http_parts::HttpPart;
 use pact_models::prelude::ContentType;
 use pact_models::v4::http_parts::HttpResponse;
 use reqwest::header::HeaderMap;
@@ -6,12 +11,20 @@ use std::collections::HashMap;
 use std::error::Error;
 use tracing::debug;
 
-pub async fn reqwest_response_to_pact(response: Response) -> Result<HttpResponse, Box<dyn Error>> {
+/// Converts the response, the body of a streaming response is not read and the response
+/// is returned with it to be read chunk by chunk, see `read_chunks`
+pub async fn reqwest_response_to_pact(
+    response: Response,
+) -> Result<(HttpResponse, Option<Response>), Box<dyn Error>> {
     let mut pact_response = HttpResponse::default();
     set_pact_response_status(&response, &mut pact_response);
     set_pact_response_headers(&response, &mut pact_response, true);
-    set_pact_response_body(response, &mut pact_response).await?;
-    Ok(pact_response)
+    if header_content_type(&pact_response).is_some_and(|content_type| is_streaming(&content_type)) {
+        return Ok((pact_response, Some(response)));
+    }
+    let body = response.bytes().await?;
+    set_pact_response_body(&mut pact_response, body);
+    Ok((pact_response, None))
 }
 
 fn set_pact_response_status(response: &Response, pact_response: &mut HttpResponse) {
@@ -27,34 +40,42 @@ fn set_pact_response_headers(
     pact_response.headers = reqwest_headers_to_pact_headers(&headers, make_small_cap);
 }
 
-async fn set_pact_response_body(
-    response: Response,
-    pact_response: &mut HttpResponse,
-) -> Result<(), Box<dyn Error>> {
-    // get content type if it is there
-    let content_type = response.headers().get("content-type");
-    match content_type {
-        Some(header_value) => {
-            // decompose content type
-            let content_type = header_value.to_str()?;
-            let content_type = ContentType::parse(content_type)?;
-            let json_body = response.bytes().await?;
-            debug!("json body: {json_body:?}");
-            pact_response.body =
-                pact_models::prelude::OptionalBody::Present(json_body, Some(content_type), None);
-        }
-        None => {
-            debug!("no content type header");
+/// Sets the body with the content type of the headers
+pub fn set_pact_response_body(pact_response: &mut HttpResponse, body: Bytes) {
+    // the body is kept whatever the headers say, see `sniff_body_content_type`
+    debug!("body: {body:?}");
+    pact_response.body = match body.is_empty() {
+        true => OptionalBody::Empty,
+        false => OptionalBody::Present(body, header_content_type(pact_response), None),
+    };
+}
+
+fn header_content_type(pact_response: &HttpResponse) -> Option<ContentType> {
+    pact_response
+        .lookup_header_value("content-type")
+        .and_then(|content_type| ContentType::parse(content_type.as_str()).ok())
+}
+
+/// Sets the content type of a body without one from its bytes, and marks the bodies
+/// that are not text as binary so that the pact stores them base64 encoded
+pub fn sniff_body_content_type(pact_response: &mut HttpResponse) {
+    if let OptionalBody::Present(body, content_type, content_type_hint) = &mut pact_response.body {
+        let content_type = content_type.get_or_insert_with(|| {
+            detect_content_type_from_bytes(body)
+                .unwrap_or_else(|| ContentType::parse("application/octet-stream").unwrap())
+        });
+        if content_type.is_binary() || std::str::from_utf8(body).is_err() {
+            debug!("binary body of type {}", content_type);
+            *content_type_hint = Some(ContentTypeHint::BINARY);
         }
     }
-    Ok(())
 }
 
 fn reqwest_headers_to_pact_headers(
     headers: &HeaderMap,
     make_small_cap: bool,
 ) -> Option<HashMap<String, Vec<String>>> {
-    let mut pact_headers = HashMap::new();
+    let mut pact_headers: HashMap<String, Vec<String>> = HashMap::new();
     for (key, value) in headers.iter() {
         let mut key = key.as_str().to_string();
         if make_small_cap {
@@ -67,8 +88,8 @@ fn reqwest_headers_to_pact_headers(
             not implemented yet"
             );
         }
-        let value = value.to_str().unwrap().to_string();
-        pact_headers.entry(key).or_insert(vec![]).push(value);
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        pact_headers.entry(key).or_default().push(value);
     }
     if pact_headers.is_empty() {
         None
@@ -79,12 +100,26 @@ fn reqwest_headers_to_pact_headers(
 
 #[cfg(test)]
 mod tests {
+    use crate::test_utils::{record_then_replay, test_pacts_folder, TestUpstream};
+    use reqwest::header::{HeaderValue, SET_COOKIE};
+    use tiny_http::Response;
+
+    #[test]
+    fn test_reqwest_headers_to_pact_headers_repeated() {
+        let mut headers = super::HeaderMap::new();
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("b=2; Path=/, c"));
+        let pact_headers = super::reqwest_headers_to_pact_headers(&headers, true).unwrap();
+        assert_eq!(pact_headers["set-cookie"], vec!["a=1", "b=2; Path=/, c"]);
+    }
+
     #[tokio::test]
     async fn test_reqwest_response_to_pact() {
         let response = reqwest::get("https://jsonplaceholder.typicode.com/todos/1")
             .await
             .unwrap();
-        let pact_response = super::reqwest_response_to_pact(response).await.unwrap();
+        let (pact_response, stream) = super::reqwest_response_to_pact(response).await.unwrap();
+        assert!(stream.is_none());
         assert_eq!(pact_response.status, 200);
         assert_eq!(
             pact_response.headers.unwrap().get("content-type").unwrap()[0],
@@ -95,4 +130,49 @@ mod tests {
             "application/json;charset=utf-8"
         );
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_binary_bodies() {
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\xff\xfe";
+        const OCTET_STREAM: &[u8] = b"\x00\x01\xc3\x28 not utf-8";
+        const NO_CONTENT_TYPE: &[u8] = b"plain text without content type";
+        let upstream = TestUpstream::start(|request| {
+            let (body, content_type) = match request.url() {
+                "/image.png" => (PNG, Some("image/png")),
+                "/blob" => (OCTET_STREAM, Some("application/octet-stream")),
+                _ => (NO_CONTENT_TYPE, None),
+            };
+            let mut response = Response::from_data(body);
+            if let Some(content_type) = content_type {
+                response.add_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+                        .unwrap(),
+                );
+            }
+            response.boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let upstream = &upstream;
+        record_then_replay(
+            &pacts_folder,
+            |builder| builder,
+            |port| async move {
+                for (path, body, content_type) in [
+                    ("/image.png", PNG, Some("image/png")),
+                    ("/blob", OCTET_STREAM, Some("application/octet-stream")),
+                    ("/raw", NO_CONTENT_TYPE, None),
+                ] {
+                    let response = reqwest::get(upstream.url(&port, path)).await.unwrap();
+                    let response_content_type = response
+                        .headers()
+                        .get("content-type")
+                        .map(|value| value.to_str().unwrap().to_string());
+                    assert_eq!(response_content_type.as_deref(), content_type);
+                    assert_eq!(response.bytes().await.unwrap(), body);
+                }
+            },
+        )
+        .await;
+        assert_eq!(upstream.hits(), 3);
+    }
 }
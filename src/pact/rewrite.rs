@@ -0,0 +1,256 @@
+#[cfg(feature = "flame_it")]
+use flamer::flame;
+use pact_models::bodies::OptionalBody;
+use pact_models::http_parts::HttpPart;
+use pact_models::v4::http_parts::HttpResponse;
+use std::error::Error;
+use url::Url;
+
+const URL_HEADERS: [&str; 3] = ["location", "content-location", "link"];
+
+/// Rewrites the absolute upstream urls in responses into proxy urls in the
+/// `/<scheme>/<host>/<path>` form, so that clients following them stay on the proxy
+#[derive(Clone, Debug)]
+pub struct UrlRewriter {
+    proxy_base: String,
+}
+
+impl UrlRewriter {
+    /// `proxy_base` is the url of the listener serving the path encoded urls, e.g. `http://localhost:8080`
+    pub fn new(proxy_base: &str) -> Self {
+        UrlRewriter {
+            proxy_base: proxy_base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns a copy of the response with the urls of the upstream of `upstream_url` rewritten
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn rewrite(
+        &self,
+        upstream_url: &str,
+        pact_response: &HttpResponse,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let upstream_url = Url::parse(upstream_url)?;
+        let origin = upstream_url.origin().ascii_serialization();
+        let proxy_prefix = self.proxy_prefix(&upstream_url)?;
+        let mut pact_response = pact_response.clone();
+        self.rewrite_headers(&mut pact_response, &origin, &proxy_prefix);
+        self.rewrite_body(&mut pact_response, &origin, &proxy_prefix);
+        Ok(pact_response)
+    }
+
+    fn proxy_prefix(&self, upstream_url: &Url) -> Result<String, Box<dyn Error>> {
+        let mut host = upstream_url
+            .host_str()
+            .ok_or("url should have host")?
+            .to_string();
+        if let Some(port) = upstream_url.port() {
+            host = host + ":" + port.to_string().as_str();
+        }
+        Ok(self.proxy_base.clone() + "/" + upstream_url.scheme() + "/" + host.as_str())
+    }
+
+    fn rewrite_headers(&self, pact_response: &mut HttpResponse, origin: &str, proxy_prefix: &str) {
+        let headers = match pact_response.headers.as_mut() {
+            Some(headers) => headers,
+            None => return,
+        };
+        for (key, values) in headers.iter_mut() {
+            let key = key.to_lowercase();
+            if !URL_HEADERS.contains(&key.as_str()) {
+                continue;
+            }
+            for value in values.iter_mut() {
+                *value = rewrite_urls(value, origin, proxy_prefix);
+                // a relative redirect would otherwise be resolved against the proxy
+                if key != "link" && value.starts_with('/') && !value.starts_with("//") {
+                    *value = proxy_prefix.to_string() + value.as_str();
+                }
+            }
+        }
+    }
+
+    fn rewrite_body(&self, pact_response: &mut HttpResponse, origin: &str, proxy_prefix: &str) {
+        let (body, content_type, content_type_hint) = match &pact_response.body {
+            OptionalBody::Present(body, content_type, content_type_hint) => {
+                (body, content_type.clone(), *content_type_hint)
+            }
+            _ => return,
+        };
+        // the urls of a compressed body are kept, its bytes are not text
+        if pact_response
+            .lookup_header_value("content-encoding")
+            .is_some()
+        {
+            return;
+        }
+        let is_text = content_type
+            .clone()
+            .or_else(|| pact_response.content_type())
+            .map(|content_type| content_type.is_text() || content_type.sub_type == "html")
+            .unwrap_or(false);
+        if !is_text {
+            return;
+        }
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+        let mut rewritten = rewrite_urls(text, origin, proxy_prefix);
+        // json serializers that escape the forward slashes
+        rewritten = rewrite_urls(
+            &rewritten,
+            &origin.replace('/', "\\/"),
+            &proxy_prefix.replace('/', "\\/"),
+        );
+        if rewritten == text {
+            return;
+        }
+        let content_length = rewritten.len();
+        pact_response.body = OptionalBody::Present(
+            rewritten.into_bytes().into(),
+            content_type,
+            content_type_hint,
+        );
+        if let Some(headers) = pact_response.headers.as_mut() {
+            for (key, values) in headers.iter_mut() {
+                if key.eq_ignore_ascii_case("content-length") {
+                    *values = vec![content_length.to_string()];
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the origin where it is the whole origin of an url,
+/// e.g. `https://api.example.com` but not `https://api.example.com.org`
+fn rewrite_urls(text: &str, origin: &str, proxy_prefix: &str) -> String {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(origin) {
+        let after = &rest[index + origin.len()..];
+        rewritten.push_str(&rest[..index]);
+        match after.chars().next() {
+            Some(c) if c.is_alphanumeric() || c == '.' || c == '-' || c == ':' || c == '_' => {
+                rewritten.push_str(origin)
+            }
+            _ => rewritten.push_str(proxy_prefix),
+        }
+        rest = after;
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use pact_models::content_types::ContentType;
+    use std::collections::HashMap;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_rewrite() {
+        let body = r#"{"next":"https://api.example.com/items?page=2","docs":"https://api.example.com.org/x"}"#;
+        let headers = HashMap::from([
+            (
+                "location".to_string(),
+                vec!["https://api.example.com/items".to_string()],
+            ),
+            (
+                "Link".to_string(),
+                vec![r#"<https://api.example.com/items?page=3>; rel="next""#.to_string()],
+            ),
+            ("content-length".to_string(), vec![body.len().to_string()]),
+        ]);
+        let pact_response = HttpResponse {
+            status: 200,
+            headers: Some(headers),
+            body: OptionalBody::Present(
+                body.into(),
+                Some(ContentType::parse("application/json").unwrap()),
+                None,
+            ),
+            ..Default::default()
+        };
+        let rewriter = UrlRewriter::new("http://localhost:8080/");
+        let rewritten = rewriter
+            .rewrite("https://api.example.com/items", &pact_response)
+            .unwrap();
+        let headers = rewritten.headers.unwrap();
+        assert_eq!(
+            headers["location"][0],
+            "http://localhost:8080/https/api.example.com/items"
+        );
+        assert_eq!(
+            headers["Link"][0],
+            r#"<http://localhost:8080/https/api.example.com/items?page=3>; rel="next""#
+        );
+        let expected_body = r#"{"next":"http://localhost:8080/https/api.example.com/items?page=2","docs":"https://api.example.com.org/x"}"#;
+        assert_eq!(rewritten.body.value().unwrap(), expected_body.as_bytes());
+        assert_eq!(
+            headers["content-length"][0],
+            expected_body.len().to_string()
+        );
+    }
+
+    #[test]
+    fn test_rewrite_relative_location() {
+        let pact_response = HttpResponse {
+            status: 302,
+            headers: Some(HashMap::from([(
+                "location".to_string(),
+                vec!["/login".to_string()],
+            )])),
+            ..Default::default()
+        };
+        let rewritten = UrlRewriter::new("http://localhost:8080")
+            .rewrite("http://localhost:9000/account", &pact_response)
+            .unwrap();
+        assert_eq!(
+            rewritten.headers.unwrap()["location"][0],
+            "http://localhost:8080/http/localhost:9000/login"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_rewrite_upstream_urls() {
+        let upstream = TestUpstream::start(|request| {
+            let host = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Host"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            Response::from_string(format!("http://{host}/page/2"))
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..])
+                        .unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        pact_server.rewrite_upstream_urls().unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://localhost:{port}/http/127.0.0.1:{}/p", upstream.port);
+        for _ in 0..2 {
+            let response = reqwest::get(url.as_str()).await.unwrap();
+            assert_eq!(
+                response.text().await.unwrap(),
+                format!(
+                    "http://localhost:{port}/http/127.0.0.1:{}/page/2",
+                    upstream.port
+                )
+            );
+        }
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+        // the pact keeps the original upstream url
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains(&format!("http://127.0.0.1:{}/page/2", upstream.port)));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Mode;
+use crate::pact::headers::split_header;
+use pact_models::v4::http_parts::HttpRequest;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Header naming the session a request is recorded in and replayed from
+pub const SESSION_HEADER: &str = "x-pact-session";
+const SESSION_PREFIX: &str = "/_session/";
+
+/// Splits `/_session/<name>/<path>` into the session name and `/<path>`
+pub fn strip_session_prefix(target: &str) -> Option<(&str, &str)> {
+    let rest = target.strip_prefix(SESSION_PREFIX)?;
+    match rest.find('/') {
+        Some(end) => Some((&rest[..end], &rest[end..])),
+        None => Some((rest, "/")),
+    }
+}
+
+/// Names the session of the request like the session header does, e.g. the session
+/// of a path prefix
+pub fn add_session_header(pact_request: &mut HttpRequest, session: &str) {
+    pact_request
+        .headers
+        .get_or_insert_with(HashMap::new)
+        .insert(SESSION_HEADER.to_string(), vec![session.to_string()]);
+}
+
+/// Session of the request and the request without the session header,
+/// which is neither forwarded nor recorded
+pub fn split_session(
+    pact_request: &HttpRequest,
+) -> Result<(Option<String>, Cow<'_, HttpRequest>), Box<dyn Error>> {
+    let (session, pact_request) = split_header(pact_request, SESSION_HEADER);
+    if let Some(session) = &session {
+        if session.is_empty()
+            || !session
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(format!("Invalid session name {session:?}").into());
+        }
+    }
+    Ok((session, pact_request))
+}
+
+/// Consumers whose pacts are searched for an interaction, new interactions are recorded
+/// to the first one. A session records its own answers and is only layered over the
+/// default recordings on replay
+pub fn consumer_layers(consumer: &str, session: Option<&str>, mode: Mode) -> Vec<String> {
+    match (session, mode) {
+        (Some(session), Mode::Replay) => {
+            vec![format!("{consumer}@{session}"), consumer.to_string()]
+        }
+        (Some(session), Mode::Record) => vec![format!("{consumer}@{session}")],
+        (None, _) => vec![consumer.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pact::session;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tiny_http::Response;
+
+    #[test]
+    fn test_split_session() {
+        assert_eq!(
+            strip_session_prefix("/_session/funded/https/api.binance.com/balance"),
+            Some(("funded", "/https/api.binance.com/balance"))
+        );
+        assert_eq!(strip_session_prefix("/https/api.binance.com"), None);
+        let mut pact_request = HttpRequest {
+            headers: Some(HashMap::from([(
+                SESSION_HEADER.to_string(),
+                vec!["funded".to_string()],
+            )])),
+            ..Default::default()
+        };
+        let (session, request) = split_session(&pact_request).unwrap();
+        assert_eq!(session.as_deref(), Some("funded"));
+        assert!(request.headers.as_ref().unwrap().is_empty());
+        pact_request.headers = Some(HashMap::from([(
+            SESSION_HEADER.to_string(),
+            vec!["../funded".to_string()],
+        )]));
+        assert!(split_session(&pact_request).is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_sessions() {
+        let upstream = TestUpstream::start({
+            let hits = AtomicUsize::new(0);
+            move |_| {
+                let hit = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                Response::from_string(hit.to_string()).boxed()
+            }
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let path = format!("/http/127.0.0.1:{}/balance", upstream.port);
+        let get = |prefix: &str, session: Option<&str>| {
+            let mut request =
+                reqwest::Client::new().get(format!("http://localhost:{port}{prefix}{path}"));
+            if let Some(session) = session {
+                request = request.header("X-Pact-Session", session);
+            }
+            async move { request.send().await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(get("", None).await, "1");
+        assert_eq!(get("/_session/funded", None).await, "2");
+        assert_eq!(get("", Some("funded")).await, "2");
+        assert_eq!(get("", None).await, "1");
+        assert_eq!(upstream.hits(), 2);
+        pact_server.stop().unwrap();
+        let pact =
+            std::fs::read_to_string(pacts_folder.join("consumer@funded-127.0.0.1.json")).unwrap();
+        assert!(!pact.contains(session::SESSION_HEADER));
+
+        // on replay the sessions without their own recording get the default one
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .mode(Mode::Replay)
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let get = |session: &str| {
+            let request = reqwest::Client::new()
+                .get(format!("http://localhost:{port}{path}"))
+                .header("X-Pact-Session", session);
+            async move { request.send().await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(get("funded").await, "2");
+        assert_eq!(get("empty").await, "1");
+        assert_eq!(upstream.hits(), 2);
+        pact_server.stop().unwrap();
+    }
+}
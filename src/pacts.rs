@@ -1,17 +1,37 @@
+use crate::config::SequenceEnd;
+use crate::pact::body_files::body_file_reference;
 use crate::pact::edit;
-use crate::server::InteractionIndexMap;
+use crate::server::{InteractionIndexMap, InteractionKey};
 use pact_models::prelude::v4::V4Pact;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use pact_models::v4::message_parts::MessageContents;
 use pact_models::{Consumer, Provider};
+use serde_json::Value;
 use std::collections::HashMap;
 use std::error::Error;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+/// Response of an interaction, a body spilled over to a file is read from `body_file`
+pub struct RecordedResponse {
+    pub response: HttpResponse,
+    pub body_file: Option<PathBuf>,
+    /// Data of the proxy stored with the interaction
+    pub metadata: HashMap<String, Value>,
+}
+
+/// Asynchronous message of a pact with the data of the proxy stored with it
+pub struct RecordedMessage {
+    pub contents: MessageContents,
+    pub metadata: HashMap<String, Value>,
+}
+
 pub struct Pacts {
     pacts: Arc<Mutex<HashMap<(String, String), V4Pact>>>,
     interaction_index_map: Arc<Mutex<InteractionIndexMap>>,
     pacts_folder: PathBuf,
+    /// Calls of each interaction key served so far, the position in its sequence
+    calls: Mutex<HashMap<(String, String, InteractionKey), usize>>,
 }
 
 impl Pacts {
@@ -24,16 +44,19 @@ impl Pacts {
             pacts,
             interaction_index_map,
             pacts_folder,
+            calls: Mutex::new(HashMap::new()),
         }
     }
 
     #[cfg_attr(feature = "flame_it", flame)]
     pub fn add_interaction(
         &self,
-        consumer: &str,
-        provider: &str,
+        (consumer, provider): &(String, String),
+        description: &str,
+        provider_state: Option<&str>,
         request: &HttpRequest,
         response: &HttpResponse,
+        metadata: HashMap<String, Value>,
     ) -> Result<(), Box<dyn Error>> {
         let mut pacts = self.pacts.lock().unwrap();
         let mut interaction_index_map = self.interaction_index_map.lock().unwrap();
@@ -42,11 +65,76 @@ impl Pacts {
             .or_insert(Self::default_empty_pact(consumer, provider));
         interaction_index_map
             .entry((consumer.to_string(), provider.to_string()))
-            .or_insert(HashMap::new());
-        edit::add_interaction_to_pact(request, response, pact, &mut interaction_index_map)?;
+            .or_default();
+        edit::add_interaction_to_pact(
+            description,
+            provider_state,
+            request,
+            response,
+            metadata,
+            pact,
+            &mut interaction_index_map,
+        )?;
+        // the recorded call counts as the last one of its sequence
+        let key = edit::interaction_key(description, provider_state);
+        let recorded =
+            interaction_index_map[&(consumer.to_string(), provider.to_string())][&key].len();
+        self.calls
+            .lock()
+            .unwrap()
+            .insert((consumer.to_string(), provider.to_string(), key), recorded);
         Ok(())
     }
 
+    /// Adds the messages in order, e.g. the frames of a websocket connection
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn add_messages(
+        &self,
+        consumer: &str,
+        provider: &str,
+        messages: Vec<(String, RecordedMessage)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut pacts = self.pacts.lock().unwrap();
+        let mut interaction_index_map = self.interaction_index_map.lock().unwrap();
+        let pact = pacts
+            .entry((consumer.to_string(), provider.to_string()))
+            .or_insert(Self::default_empty_pact(consumer, provider));
+        interaction_index_map
+            .entry((consumer.to_string(), provider.to_string()))
+            .or_default();
+        for (description, message) in messages {
+            edit::add_message_to_pact(
+                &description,
+                &message.contents,
+                message.metadata,
+                pact,
+                &mut interaction_index_map,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Asynchronous messages of the pact in the order they were recorded
+    #[cfg_attr(feature = "flame_it", flame)]
+    pub fn get_messages(&self, consumer: &str, provider: &str) -> Vec<RecordedMessage> {
+        let pacts = self.pacts.lock().unwrap();
+        let pact = match pacts.get(&(consumer.to_string(), provider.to_string())) {
+            Some(pact) => pact,
+            None => return vec![],
+        };
+        pact.interactions
+            .iter()
+            .filter_map(|interaction| interaction.as_v4_async_message())
+            .map(|mut message| RecordedMessage {
+                metadata: message
+                    .plugin_config
+                    .remove(edit::PROXY_PLUGIN)
+                    .unwrap_or_default(),
+                contents: message.contents,
+            })
+            .collect()
+    }
+
     #[cfg_attr(feature = "flame_it", flame)]
     fn default_empty_pact(consumer: &str, provider: &str) -> V4Pact {
         let consumer = Consumer {
@@ -70,17 +158,66 @@ impl Pacts {
         consumer: &str,
         provider: &str,
         interaction_descr: &str,
-    ) -> Option<HttpResponse> {
+        provider_state: Option<&str>,
+        sequence_end: Option<SequenceEnd>,
+    ) -> Option<RecordedResponse> {
         let pacts = self.pacts.lock().unwrap();
         let interaction_index_map = self.interaction_index_map.lock().unwrap();
         let pact = pacts.get(&(consumer.to_string(), provider.to_string()))?;
-        let interaction_index = interaction_index_map
+        let key = edit::interaction_key(interaction_descr, provider_state);
+        let indexes = interaction_index_map
             .get(&(consumer.to_string(), provider.to_string()))?
-            .get(interaction_descr)?;
-        let interaction = pact.interactions.get(*interaction_index as usize)?;
+            .get(&key)?;
+        let position = match sequence_end {
+            Some(sequence_end) => {
+                self.next_call(consumer, provider, key, indexes.len(), sequence_end)?
+            }
+            None => 0,
+        };
+        let interaction = pact.interactions.get(*indexes.get(position)? as usize)?;
         let interaction_json = interaction.to_json();
         let pact_response = HttpResponse::from_json(interaction_json.get("response")?).ok()?;
-        Some(pact_response)
+        let metadata = interaction
+            .plugin_config()
+            .remove(edit::PROXY_PLUGIN)
+            .unwrap_or_default();
+        let body_file =
+            body_file_reference(&metadata).map(|reference| self.pacts_folder.join(reference));
+        Some(RecordedResponse {
+            response: pact_response,
+            body_file,
+            metadata,
+        })
+    }
+
+    /// Position of the call in the sequence of `recorded` interactions, `None` past the end
+    /// of the sequence unless it goes on
+    fn next_call(
+        &self,
+        consumer: &str,
+        provider: &str,
+        key: InteractionKey,
+        recorded: usize,
+        sequence_end: SequenceEnd,
+    ) -> Option<usize> {
+        let mut calls = self.calls.lock().unwrap();
+        let call = calls
+            .entry((consumer.to_string(), provider.to_string(), key))
+            .or_default();
+        let position = match sequence_end {
+            _ if *call < recorded => *call,
+            SequenceEnd::RepeatLast => recorded - 1,
+            SequenceEnd::Cycle => *call % recorded,
+            // the call is recorded at this position or fails
+            SequenceEnd::Error => return None,
+        };
+        *call += 1;
+        Some(position)
+    }
+
+    /// Starts the sequences over from their first recording
+    pub fn reset_sequences(&self) {
+        self.calls.lock().unwrap().clear();
     }
 
     pub fn get_folder(&self) -> PathBuf {
@@ -97,3 +234,143 @@ impl Pacts {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Mode;
+    use crate::pact::headers::PROVIDER_STATE_HEADER;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tiny_http::Response;
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_provider_states() {
+        let upstream = TestUpstream::start({
+            let hits = AtomicUsize::new(0);
+            move |_| {
+                let hit = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                Response::from_string(hit.to_string()).boxed()
+            }
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/account",
+            upstream.port
+        );
+        let get = |provider_state: Option<&str>| {
+            let mut request = reqwest::Client::new().get(url.as_str());
+            if let Some(provider_state) = provider_state {
+                request = request.header("X-Pact-Provider-State", provider_state);
+            }
+            async move { request.send().await.unwrap().text().await.unwrap() }
+        };
+        assert_eq!(get(None).await, "1");
+        pact_server.set_provider_state(Some("a funded account"));
+        assert_eq!(get(None).await, "2");
+        assert_eq!(get(Some("an empty account")).await, "3");
+        assert_eq!(get(None).await, "2");
+        pact_server.set_provider_state(None);
+        assert_eq!(get(None).await, "1");
+        assert_eq!(upstream.hits(), 3);
+        pact_server.stop().unwrap();
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains(r#""name": "a funded account""#));
+        assert!(!pact.contains(PROVIDER_STATE_HEADER));
+
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .provider_state("an empty account")
+            .mode(Mode::Replay)
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/account",
+            upstream.port
+        );
+        assert_eq!(reqwest::get(url).await.unwrap().text().await.unwrap(), "3");
+        pact_server.stop().unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_sequences() {
+        let upstream = TestUpstream::start({
+            let hits = AtomicUsize::new(0);
+            move |_| {
+                let status = ["pending", "running", "done"][hits.fetch_add(1, Ordering::SeqCst)];
+                Response::from_string(status).boxed()
+            }
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        pact_server.replay_sequences(SequenceEnd::Error);
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/job",
+            upstream.port
+        );
+        for status in ["pending", "running", "done"] {
+            let response = reqwest::get(url.as_str()).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), status);
+        }
+        pact_server.reset_sequences();
+        let response = reqwest::get(url.as_str()).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "pending");
+        assert_eq!(upstream.hits(), 3);
+        pact_server.stop().unwrap();
+
+        let replayed = [
+            (
+                SequenceEnd::RepeatLast,
+                ["pending", "running", "done", "done"],
+            ),
+            (
+                SequenceEnd::Cycle,
+                ["pending", "running", "done", "pending"],
+            ),
+        ];
+        for (end, statuses) in replayed {
+            let mut pact_server = PactServer::builder()
+                .pacts_folder(&pacts_folder)
+                .mode(Mode::Replay)
+                .sequence(end)
+                .build()
+                .unwrap();
+            let port = pact_server.port().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            let url = format!(
+                "http://localhost:{port}/http/127.0.0.1:{}/job",
+                upstream.port
+            );
+            for status in statuses {
+                let response = reqwest::get(url.as_str()).await.unwrap();
+                assert_eq!(response.text().await.unwrap(), status);
+            }
+            pact_server.stop().unwrap();
+        }
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .mode(Mode::Replay)
+            .sequence(SequenceEnd::Error)
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/job",
+            upstream.port
+        );
+        for status in [200, 200, 200, 404] {
+            assert_eq!(reqwest::get(url.as_str()).await.unwrap().status(), status);
+        }
+        pact_server.stop().unwrap();
+        assert_eq!(upstream.hits(), 3);
+    }
+}
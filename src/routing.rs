@@ -0,0 +1,300 @@
+use crate::server::Port;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use tiny_http::Header;
+use url::Url;
+
+const DEFAULT_SCHEME: &str = "http";
+
+/// How the upstream url is derived from the request received by a listener
+#[derive(Clone, Debug)]
+pub enum Routing {
+    /// `/<scheme>/<host>/<path>` or the absolute url sent to a forward proxy
+    PathEncoded,
+    /// Plain paths are proxied to the fixed upstream
+    Upstream(Url),
+    /// The upstream host is the `Host` header, e.g. when clients are pointed
+    /// at the proxy with `/etc/hosts` or a dns override
+    HostHeader(SchemeMap),
+}
+
+impl Routing {
+    pub fn forward_url(
+        &self,
+        request_url: &str,
+        headers: &[Header],
+    ) -> Result<Url, Box<dyn Error>> {
+        match self {
+            Routing::PathEncoded => path_encoded_url(request_url),
+            Routing::Upstream(upstream) => {
+                let upstream = upstream.as_str().trim_end_matches('/');
+                Ok(Url::parse(&(upstream.to_owned() + request_url))?)
+            }
+            Routing::HostHeader(schemes) => {
+                if is_absolute_form(request_url) {
+                    return Ok(Url::parse(request_url)?);
+                }
+                let host = headers
+                    .iter()
+                    .find(|header| header.field.equiv("Host"))
+                    .ok_or("Host header is required for host header routing")?
+                    .value
+                    .as_str();
+                let scheme = schemes.scheme(host);
+                Ok(Url::parse(
+                    &(scheme.to_owned() + "://" + host + request_url),
+                )?)
+            }
+        }
+    }
+}
+
+/// Scheme of the upstream hosts for host header routing
+#[derive(Clone, Debug)]
+pub struct SchemeMap {
+    default_scheme: String,
+    schemes: HashMap<String, String>,
+}
+
+impl Default for SchemeMap {
+    fn default() -> Self {
+        SchemeMap {
+            default_scheme: DEFAULT_SCHEME.to_string(),
+            schemes: HashMap::new(),
+        }
+    }
+}
+
+impl SchemeMap {
+    /// Sets the scheme of a host, `host:port` or `*` for every other host
+    pub fn insert(&mut self, host: &str, scheme: &str) {
+        match host {
+            "*" => self.default_scheme = scheme.to_string(),
+            host => {
+                self.schemes.insert(host.to_lowercase(), scheme.to_string());
+            }
+        }
+    }
+
+    /// Adds an entry written as `<host>=<scheme>`
+    pub fn insert_entry(&mut self, entry: &str) -> Result<(), Box<dyn Error>> {
+        let (host, scheme) = entry
+            .split_once('=')
+            .ok_or("Scheme should be <host>=<scheme>")?;
+        let scheme = scheme.trim();
+        if scheme != "http" && scheme != "https" {
+            return Err(format!("Unsupported scheme {scheme}").into());
+        }
+        self.insert(host.trim(), scheme);
+        Ok(())
+    }
+
+    fn scheme(&self, host: &str) -> &str {
+        let host = host.to_lowercase();
+        let host_without_port = match host.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => host.as_str(),
+        };
+        self.schemes
+            .get(&host)
+            .or_else(|| self.schemes.get(host_without_port))
+            .unwrap_or(&self.default_scheme)
+    }
+}
+
+fn path_encoded_url(request_url: &str) -> Result<Url, Box<dyn Error>> {
+    // a client using the proxy as a standard http proxy sends the absolute url
+    if is_absolute_form(request_url) {
+        return Ok(Url::parse(request_url)?);
+    }
+    // remove / from the beginning of the url if it exists
+    let relative_url = match request_url.strip_prefix('/') {
+        Some(url) => url,
+        None => request_url,
+    };
+    let scheme = relative_url.split('/').next().unwrap().to_string();
+    let host = relative_url
+        .split('/')
+        .nth(1)
+        .ok_or("url should have host")?;
+    let path = relative_url
+        .split('/')
+        .skip(2)
+        .collect::<Vec<&str>>()
+        .join("/");
+    Ok(Url::parse(&(scheme + "://" + host + "/" + path.as_str()))?)
+}
+
+fn is_absolute_form(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Listener port proxying to a fixed upstream, written as `<port>=<upstream url>`
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// Random port when not set
+    pub port: Option<Port>,
+    pub upstream: Url,
+}
+
+impl FromStr for Route {
+    type Err = Box<dyn Error>;
+
+    fn from_str(route: &str) -> Result<Self, Self::Err> {
+        let (port, upstream) = route
+            .split_once("=>")
+            .or_else(|| route.split_once('='))
+            .ok_or("Route should be <port>=<upstream url>")?;
+        let port = port.trim();
+        port.parse::<u16>()?;
+        Ok(Route {
+            port: Some(port.to_string()),
+            upstream: Url::parse(upstream.trim())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use serde_json::Value;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_forward_url() {
+        assert_eq!(
+            Routing::PathEncoded
+                .forward_url("/https/api.binance.com/api/v3/klines?limit=1", &[])
+                .unwrap()
+                .as_str(),
+            "https://api.binance.com/api/v3/klines?limit=1"
+        );
+        let route = Route::from_str("9001 => https://api.binance.com/api").unwrap();
+        assert_eq!(route.port, Some("9001".to_string()));
+        assert_eq!(
+            Routing::Upstream(route.upstream)
+                .forward_url("/v3/klines?limit=1", &[])
+                .unwrap()
+                .as_str(),
+            "https://api.binance.com/api/v3/klines?limit=1"
+        );
+        assert!(Route::from_str("https://api.binance.com").is_err());
+    }
+
+    #[test]
+    fn test_host_header_forward_url() {
+        let mut schemes = SchemeMap::default();
+        schemes.insert_entry("api.binance.com=https").unwrap();
+        let routing = Routing::HostHeader(schemes);
+        let host = |value: &str| Header::from_bytes(&b"Host"[..], value.as_bytes()).unwrap();
+        assert_eq!(
+            routing
+                .forward_url("/api/v3/klines?limit=1", &[host("api.binance.com")])
+                .unwrap()
+                .as_str(),
+            "https://api.binance.com/api/v3/klines?limit=1"
+        );
+        assert_eq!(
+            routing
+                .forward_url("/status", &[host("localhost:8080")])
+                .unwrap()
+                .as_str(),
+            "http://localhost:8080/status"
+        );
+        assert!(routing.forward_url("/status", &[]).is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_route() {
+        let upstream =
+            TestUpstream::start(|request| Response::from_string(request.url().to_string()).boxed());
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let route_port = pact_server
+            .add_route(Route {
+                port: None,
+                upstream: url::Url::parse(&format!("http://127.0.0.1:{}/base", upstream.port))
+                    .unwrap(),
+            })
+            .unwrap();
+        let readiness = pact_server.readiness().unwrap();
+        assert_eq!(readiness["port"].to_string(), pact_server.port().unwrap());
+        assert_eq!(readiness["routes"][0]["port"].to_string(), route_port);
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!("http://localhost:{route_port}/routed?a=1");
+        for _ in 0..2 {
+            let response = reqwest::get(url.as_str()).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.text().await.unwrap(), "/base/routed?a=1");
+        }
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+        assert!(pacts_folder.join("consumer-127.0.0.1.json").exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_host_header_routing() {
+        let upstream = TestUpstream::start(|request| {
+            let host = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Host"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            Response::from_string(host).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server
+            .add_host_header_routing(None, SchemeMap::default())
+            .unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let upstream_host = format!("127.0.0.1:{}", upstream.port);
+        for _ in 0..2 {
+            let response = reqwest::Client::new()
+                .get(format!("http://localhost:{port}/virtual"))
+                .header("Host", upstream_host.as_str())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            // the upstream gets the real host
+            assert_eq!(response.text().await.unwrap(), upstream_host);
+        }
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_forward_proxy() {
+        let upstream = TestUpstream::start(|_| {
+            Response::from_string(r#"{"mode": "forward proxy"}"#)
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://localhost:{port}")).unwrap())
+            .build()
+            .unwrap();
+        let url = format!("http://127.0.0.1:{}/forward?a=1", upstream.port);
+        for _ in 0..2 {
+            let response = client.get(url.as_str()).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            let body = response.json::<Value>().await.unwrap();
+            assert_eq!(body["mode"], "forward proxy");
+        }
+        // second request is served from the recorded pact
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+    }
+}
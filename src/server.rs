@@ -1,49 +1,116 @@
-use crate::cli::get_rand_port;
+use crate::builder::PactServerBuilder;
+use crate::config::{InFlightRequest, Mode, ProxyConfig, SequenceEnd};
+use crate::grpc::{Descriptors, GrpcServer};
+use crate::listener::{ListenAddr, LocalAddr};
+use crate::mitm::{Front, Interceptor};
+use crate::pact::body_files::{body_file_metadata, read_body_file, spill_body};
+use crate::pact::chunks::{chunks_metadata, decoded_chunks, read_chunks, recorded_chunks};
 use crate::pact::edit;
-use crate::pact::pact_to_response::pact_response_to_http_response;
-use crate::pacts::Pacts;
+use crate::pact::encoding::{body_encodings, response_encoding};
+use crate::pact::headers::{add_forwarded_headers, add_via, split_header, PROVIDER_STATE_HEADER};
+use crate::pact::pact_to_response::{
+    pact_response_to_http_response, pact_response_to_streamed_http_response, PacedResponse,
+};
+use crate::pact::response_to_pact::set_pact_response_body;
+use crate::pact::rewrite::UrlRewriter;
+use crate::pact::session;
+use crate::pacts::{Pacts, RecordedResponse};
+use crate::routing::{Route, Routing, SchemeMap};
+use crate::tls::TlsCertificate;
+use crate::web::UpstreamResponse;
 use crate::{pact::request_to_pact::http_request_to_pact, web};
+use bytes::Bytes;
 #[cfg(feature = "flame_it")]
 use flamer::flame;
+use pact_models::bodies::OptionalBody;
+use pact_models::http_parts::HttpPart;
 use pact_models::prelude::v4::V4Pact;
 use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::Cursor;
-use std::path::Path;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{io, thread};
-use tiny_http::{Response, Server};
-use tracing::debug;
+use tiny_http::{Method, Request, Response, ResponseBox, Server};
+use tokio::sync::oneshot;
+use tokio::task;
+use tracing::{debug, Instrument};
+use url::Url;
 
-pub type InteractionIndexMap = HashMap<(String, String), HashMap<String, u16>>;
+/// Description and provider state of an interaction
+pub type InteractionKey = (String, Option<String>);
+/// Indexes of the interactions of each pact by key, in the order they were recorded
+pub type InteractionIndexMap = HashMap<(String, String), HashMap<InteractionKey, Vec<u16>>>;
 pub type Port = String;
 
+/// Port 0, the OS assigns a free port when the listener is bound
+const ANY_PORT: &str = "0";
+
+/// Response for a client, the streams recorded with their timing are paced
+pub(crate) enum ClientResponse {
+    Buffered(ResponseBox),
+    Paced(Box<PacedResponse>),
+    /// Stream of the upstream relayed while the future reads and records it,
+    /// the future has to run for the stream to be written
+    Relayed(Box<PacedResponse>, Pin<Box<dyn Future<Output = ()> + Send>>),
+}
+
 enum WrappedServer {
     Httpserver(Arc<Server>),
-    Grpc,
+    Grpc(Arc<GrpcServer>),
+}
+
+/// Additional listener with its own routing, a fixed upstream or the Host header
+struct RouteServer {
+    server: Arc<Server>,
+    routing: Routing,
+    server_thread: Option<JoinHandle<Result<(), String>>>,
 }
 
 pub struct PactServer {
     server: WrappedServer,
     server_thread: Option<JoinHandle<Result<(), String>>>,
     pacts: Arc<Pacts>,
+    front: Option<Arc<Front>>,
+    front_thread: Option<JoinHandle<()>>,
+    route_servers: Vec<RouteServer>,
+    tls_cert_path: Option<PathBuf>,
+    pub(crate) config: ProxyConfig,
 }
 
 impl PactServer {
-    pub fn port(&self) -> Result<Port, Box<dyn Error>> {
-        Ok(match &self.server {
-            WrappedServer::Httpserver(server) => server
-                .server_addr()
-                .to_ip()
-                .ok_or("No port")?
-                .port()
-                .to_string(),
-            WrappedServer::Grpc => {
-                todo!()
+    /// Builder with typed options, validated together by `build`
+    pub fn builder() -> PactServerBuilder {
+        PactServerBuilder::default()
+    }
+
+    /// Address the clients connect to, the front when there is one
+    pub fn local_addr(&self) -> Result<LocalAddr, Box<dyn Error>> {
+        if let Some(front) = &self.front {
+            return Ok(front.local_addr()?);
+        }
+        Ok(LocalAddr::Tcp(match &self.server {
+            WrappedServer::Httpserver(server) => {
+                server.server_addr().to_ip().ok_or("No address")?
             }
-        })
+            WrappedServer::Grpc(server) => server.local_addr(),
+        }))
+    }
+
+    pub fn port(&self) -> Result<Port, Box<dyn Error>> {
+        let local_addr = self.local_addr()?;
+        let port = local_addr
+            .port()
+            .ok_or(format!("{local_addr} has no port"))?;
+        Ok(port.to_string())
     }
 
     pub fn with_http_server(
@@ -51,59 +118,430 @@ impl PactServer {
         pacts: Option<HashMap<(String, String), V4Pact>>,
         port: Option<Port>,
     ) -> Result<Self, Box<dyn Error>> {
-        let port = match port {
-            Some(port) => port,
-            None => get_rand_port().to_string(),
-        };
-        let pacts = match pacts {
-            Some(pacts) => pacts,
-            None => edit::read_pacts(pacts_folder)?,
-        };
-        let interaction_index_map = make_interaction_index_map(&pacts);
-        let pacts = Arc::new(Pacts::new(
-            Arc::new(Mutex::new(pacts)),
-            Arc::new(Mutex::new(interaction_index_map)),
-            pacts_folder.to_path_buf(),
-        ));
+        let port = port.unwrap_or_else(|| ANY_PORT.to_string());
+        Self::listen(pacts_folder, pacts, &ListenAddr::localhost(&port), None)
+    }
+
+    /// Http server listening on any address, e.g. `0.0.0.0:8080`, `[::]:8080`
+    /// or a unix socket. Https is intercepted when a `ca_folder` is given
+    pub fn listen(
+        pacts_folder: &Path,
+        pacts: Option<HashMap<(String, String), V4Pact>>,
+        addr: &ListenAddr,
+        ca_folder: Option<&Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let interceptor = ca_folder
+            .map(|ca_folder| Interceptor::new(ca_folder).map(Arc::new))
+            .transpose()?;
+        let (server, front) = make_fronted_http_server(addr, interceptor)?;
+        Ok(PactServer {
+            server,
+            server_thread: None,
+            pacts: make_pacts(pacts_folder, pacts)?,
+            front: Some(Arc::new(front)),
+            front_thread: None,
+            route_servers: Vec::new(),
+            tls_cert_path: None,
+            config: ProxyConfig::default(),
+        })
+    }
+
+    /// Http server that also intercepts https traffic sent through the proxy with CONNECT.
+    /// The CA certificate that the clients have to trust is written to `ca_folder`
+    pub fn with_https_interception(
+        pacts_folder: &Path,
+        pacts: Option<HashMap<(String, String), V4Pact>>,
+        port: Option<Port>,
+        ca_folder: &Path,
+    ) -> Result<Self, Box<dyn Error>> {
+        let port = port.unwrap_or_else(|| ANY_PORT.to_string());
+        Self::listen(
+            pacts_folder,
+            pacts,
+            &ListenAddr::localhost(&port),
+            Some(ca_folder),
+        )
+    }
+
+    /// Http/2 server recording the unary grpc calls to `upstream`. The messages are stored
+    /// as json when their types are in the `descriptor_set` file
+    pub fn with_grpc_server(
+        pacts_folder: &Path,
+        pacts: Option<HashMap<(String, String), V4Pact>>,
+        port: Option<Port>,
+        upstream: Url,
+        descriptor_set: Option<&Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let port = port.unwrap_or_else(|| ANY_PORT.to_string());
+        let descriptors = descriptor_set.map(Descriptors::from_file).transpose()?;
+        let server = GrpcServer::bind(&("localhost:".to_owned() + &port), upstream, descriptors)?;
         Ok(PactServer {
-            server: make_http_server(port)?,
+            server: WrappedServer::Grpc(Arc::new(server)),
             server_thread: None,
-            pacts: pacts,
+            pacts: make_pacts(pacts_folder, pacts)?,
+            front: None,
+            front_thread: None,
+            route_servers: Vec::new(),
+            tls_cert_path: None,
+            config: ProxyConfig::default(),
         })
     }
 
+    /// Addresses of the listeners as json, for test runners discovering the proxy,
+    /// e.g. `{"ready":true,"address":"127.0.0.1:36001","port":36001,"routes":[]}`
+    pub fn readiness(&self) -> Result<Value, Box<dyn Error>> {
+        let local_addr = self.local_addr()?;
+        let routes = self
+            .route_servers
+            .iter()
+            .map(|route_server| {
+                let port = route_server
+                    .server
+                    .server_addr()
+                    .to_ip()
+                    .map(|addr| addr.port());
+                match &route_server.routing {
+                    Routing::Upstream(upstream) => {
+                        json!({"port": port, "upstream": upstream.as_str()})
+                    }
+                    _ => json!({"port": port, "hostHeader": true}),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut readiness = json!({
+            "ready": true,
+            "address": local_addr.to_string(),
+            "port": local_addr.port(),
+            "routes": routes,
+        });
+        if let Some(ca_cert_path) = self.ca_cert_path() {
+            readiness["caCertificate"] = json!(ca_cert_path);
+        }
+        if let Some(tls_cert_path) = self.tls_cert_path() {
+            readiness["tlsCertificate"] = json!(tls_cert_path);
+        }
+        Ok(readiness)
+    }
+
+    /// Path of the CA certificate when https is intercepted
+    pub fn ca_cert_path(&self) -> Option<PathBuf> {
+        self.front.as_ref()?.ca_cert_path().map(Path::to_path_buf)
+    }
+
+    /// Path of the certificate of the https served with `serve_tls`
+    pub fn tls_cert_path(&self) -> Option<&Path> {
+        self.tls_cert_path.as_deref()
+    }
+
+    /// Serves https instead of http with the certificate, before the server is started.
+    /// The websockets are then accepted as `wss://`
+    pub fn serve_tls(&mut self, certificate: &TlsCertificate) -> Result<(), Box<dyn Error>> {
+        let front = self
+            .front
+            .as_mut()
+            .ok_or("The grpc server does not serve tls")?;
+        Arc::get_mut(front)
+            .ok_or("Tls has to be served before the server is started")?
+            .serve_tls(certificate.server_config()?);
+        self.tls_cert_path = Some(certificate.cert_path());
+        Ok(())
+    }
+
+    /// Rewrites the absolute upstream urls in the responses into path encoded urls of this proxy.
+    /// The recorded pacts keep the original urls
+    pub fn rewrite_upstream_urls(&mut self) -> Result<(), Box<dyn Error>> {
+        let scheme = match &self.front {
+            Some(front) if front.is_tls() => "https",
+            _ => "http",
+        };
+        let proxy_base = format!("{scheme}://localhost:{}", self.port()?);
+        self.config.url_rewriter = Some(UrlRewriter::new(&proxy_base));
+        Ok(())
+    }
+
+    /// Returns the upstream redirects to the clients instead of following them.
+    /// Every hop is recorded as its own interaction and replayed as a redirect
+    pub fn record_redirects(&mut self) {
+        self.config.record_redirects = true;
+    }
+
+    /// Records and replays the exact bytes of the upstream bodies, e.g. for clients verifying
+    /// signatures of the responses. Bodies that would not survive the json normalization
+    /// are stored base64 encoded, compressed bodies are not decoded
+    pub fn keep_exact_bodies(&mut self) {
+        self.config.exact_bodies = true;
+    }
+
+    /// Adds `1.1 <pseudonym>` to the `Via` headers of the forwarded requests and of the responses
+    pub fn add_via_header(&mut self, pseudonym: &str) {
+        self.config.via = Some(pseudonym.to_string());
+    }
+
+    /// Adds `X-Forwarded-For`, `X-Forwarded-Host` and `X-Forwarded-Proto` to the requests
+    pub fn add_forwarded_headers(&mut self) {
+        self.config.forwarded_headers = true;
+    }
+
+    /// Stores the bodies larger than `bytes` in files named by their hash next to the pacts,
+    /// they are streamed from the files on replay
+    pub fn spill_bodies_over(&mut self, bytes: usize) {
+        self.config.spill_bodies_over = Some(bytes);
+    }
+
+    /// Records every repeated call of an interaction and replays them in the order they were
+    /// recorded, `end` is what the calls get once they went past the last recording
+    pub fn replay_sequences(&mut self, end: SequenceEnd) {
+        self.config.sequence = Some(end);
+    }
+
+    /// Starts the sequences over from their first recording, e.g. between tests
+    pub fn reset_sequences(&self) {
+        self.pacts.reset_sequences();
+    }
+
+    /// Records and replays the requests in the provider state, e.g. `an account with balance`,
+    /// until it is changed. The `X-Pact-Provider-State` header of a request takes precedence
+    pub fn set_provider_state(&self, provider_state: Option<&str>) {
+        *self.config.provider_state.lock().unwrap() = provider_state.map(str::to_string);
+    }
+
+    /// Replays the streaming responses `factor` times faster than they were recorded,
+    /// 0 sends the chunks without delays
+    pub fn speed_up_streams(&mut self, factor: f64) {
+        self.config.stream_speed_up = Some(factor);
+    }
+
+    fn start_front(&mut self, config: &Arc<ProxyConfig>) {
+        if let Some(front) = &self.front {
+            self.front_thread = Some(Front::spawn(
+                front.clone(),
+                self.pacts.clone(),
+                config.clone(),
+            ));
+        }
+    }
+
+    fn stop_front(&mut self) {
+        if let Some(front) = &self.front {
+            front.stop();
+            if let Some(front_thread) = self.front_thread.take() {
+                if front_thread.join().is_err() {
+                    debug!("Front thread panicked");
+                }
+            }
+        }
+    }
+
+    /// Adds a listener that proxies plain paths to the upstream of the route.
+    /// Returns the port of the listener
+    pub fn add_route(&mut self, route: Route) -> Result<Port, Box<dyn Error>> {
+        debug!("Route to {}", route.upstream);
+        self.add_route_server(route.port, Routing::Upstream(route.upstream))
+    }
+
+    /// Adds a listener that takes the upstream host from the `Host` header
+    /// and its scheme from the map. Returns the port of the listener
+    pub fn add_host_header_routing(
+        &mut self,
+        port: Option<Port>,
+        schemes: SchemeMap,
+    ) -> Result<Port, Box<dyn Error>> {
+        debug!("Host header routing with schemes {:?}", schemes);
+        self.add_route_server(port, Routing::HostHeader(schemes))
+    }
+
+    fn add_route_server(
+        &mut self,
+        port: Option<Port>,
+        routing: Routing,
+    ) -> Result<Port, Box<dyn Error>> {
+        let port = port.unwrap_or_else(|| ANY_PORT.to_string());
+        // on the host the proxy listens on, localhost next to a unix socket
+        let addr = match self.local_addr()? {
+            LocalAddr::Tcp(addr) => SocketAddr::new(addr.ip(), port.parse()?).to_string(),
+            LocalAddr::Unix(_) => "localhost:".to_owned() + port.as_str(),
+        };
+        let server = bind_http_server(&addr)?;
+        let port = server.server_addr().to_ip().ok_or("No port")?.port();
+        self.route_servers.push(RouteServer {
+            server,
+            routing,
+            server_thread: None,
+        });
+        Ok(port.to_string())
+    }
+
+    fn start_route_servers(&mut self, config: &Arc<ProxyConfig>) {
+        for route_server in self.route_servers.iter_mut() {
+            route_server.server_thread = spawn_thread_with_http_server(
+                self.pacts.clone(),
+                config.clone(),
+                route_server.server.clone(),
+                route_server.routing.clone(),
+            );
+        }
+    }
+
+    /// Serves the routes as tasks of the current runtime, in the current span
+    fn spawn_route_servers(
+        &self,
+        config: &Arc<ProxyConfig>,
+    ) -> Vec<task::JoinHandle<Result<(), String>>> {
+        self.route_servers
+            .iter()
+            .map(|route_server| {
+                let server = route_server.server.clone();
+                let routing = route_server.routing.clone();
+                let pacts = self.pacts.clone();
+                let config = config.clone();
+                let task = async move {
+                    run_http_server(server, pacts, config, &routing)
+                        .await
+                        .map_err(|e| e.to_string())
+                };
+                tokio::spawn(task.in_current_span())
+            })
+            .collect()
+    }
+
+    fn stop_route_servers(&mut self) -> Result<(), Box<dyn Error>> {
+        for route_server in self.route_servers.iter_mut() {
+            route_server.server.unblock();
+            if let Some(server_thread) = route_server.server_thread.take() {
+                server_thread
+                    .join()
+                    .map_err(|_| "Route server thread panicked")??;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn start_blocking(&mut self) -> Result<(), Box<dyn Error>> {
         match &self.server {
             WrappedServer::Httpserver(server) => {
-                Ok(run_http_server(server.clone().as_ref(), self.pacts.clone()).await?)
+                let server = server.clone();
+                let config = Arc::new(self.config.clone());
+                self.start_front(&config);
+                self.start_route_servers(&config);
+                let pacts = self.pacts.clone();
+                Ok(run_http_server(server, pacts, config, &Routing::PathEncoded).await?)
             }
-            WrappedServer::Grpc => {
-                unimplemented!()
+            WrappedServer::Grpc(server) => {
+                let config = Arc::new(self.config.clone());
+                Ok(server.clone().serve(self.pacts.clone(), config).await?)
             }
         }
     }
 
-    pub async fn start_non_blocking(&mut self) -> io::Result<()> {
-        let port = self.port().expect("No port");
+    /// Serves in background threads until the returned guard is dropped, also when
+    /// the test panics, or until the server is stopped through the guard
+    pub async fn start_non_blocking(&mut self) -> io::Result<ServerGuard<'_>> {
+        self.start_threads();
+        Ok(ServerGuard { server: self })
+    }
+
+    /// Starts the servers and the listeners in threads with runtimes of their own
+    pub(crate) fn start_threads(&mut self) {
+        let local_addr = self.local_addr().expect("No address");
         match &self.server {
             WrappedServer::Httpserver(server) => {
-                debug!("Starting pact server on port {}", port);
+                debug!("Starting pact server on {}", local_addr);
                 let pacts = self.pacts.clone();
                 let server = server.clone();
-                self.server_thread = spawn_thread_with_http_server(pacts, server);
+                let config = Arc::new(self.config.clone());
+                self.server_thread = spawn_thread_with_http_server(
+                    pacts,
+                    config.clone(),
+                    server,
+                    Routing::PathEncoded,
+                );
+                self.start_front(&config);
+                self.start_route_servers(&config);
             }
-            WrappedServer::Grpc => {
-                unimplemented!()
+            WrappedServer::Grpc(server) => {
+                debug!("Starting grpc server on {}", local_addr);
+                let pacts = self.pacts.clone();
+                let server = server.clone();
+                let config = Arc::new(self.config.clone());
+                self.server_thread = Some(thread::spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .map_err(|e| e.to_string())?;
+                    runtime
+                        .block_on(server.serve(pacts, config))
+                        .map_err(|e| e.to_string())
+                }));
             }
         }
-        Ok(())
     }
 
+    /// Serves as a task of the current runtime, e.g. of a `#[tokio::test]`, instead of in a
+    /// thread with a runtime of its own. The http server and the routes serve their requests
+    /// as tasks in the current span. The front still accepts its connections in threads, the
+    /// requests it passes through are served by the tasks while the intercepted tunnels and
+    /// the websockets run on runtimes of their own, outside of the span. The task ends once
+    /// the handle shuts the server down
+    pub fn spawn(mut self) -> (task::JoinHandle<Result<(), String>>, ShutdownHandle) {
+        let (sender, receiver) = oneshot::channel();
+        let task = async move {
+            let config = Arc::new(self.config.clone());
+            debug!("Spawning pact server on {:?}", self.local_addr());
+            self.start_front(&config);
+            let route_tasks = self.spawn_route_servers(&config);
+            let pacts = self.pacts.clone();
+            let result = match &self.server {
+                WrappedServer::Httpserver(server) => {
+                    let front = self.front.clone();
+                    run_http_server_task(server.clone(), pacts, config, receiver, front).await;
+                    Ok(())
+                }
+                WrappedServer::Grpc(server) => {
+                    let stopped = server.clone();
+                    tokio::spawn(async move {
+                        let _ = receiver.await;
+                        stopped.stop();
+                    });
+                    server
+                        .clone()
+                        .serve(pacts, config)
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+            };
+            self.stop_front();
+            self.stop_route_servers().map_err(|e| e.to_string())?;
+            for route_task in route_tasks {
+                route_task.await.map_err(|e| e.to_string())??;
+            }
+            result
+        };
+        (tokio::spawn(task.in_current_span()), ShutdownHandle(sender))
+    }
+
+    /// Stops accepting connections and waits up to `timeout` for the requests being served,
+    /// e.g. streams and websockets, before stopping the server
+    pub async fn shutdown(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        self.stop_front();
+        let deadline = Instant::now() + timeout;
+        while !self.config.in_flight.is_idle() {
+            if Instant::now() >= deadline {
+                debug!("Requests still in flight after {:?}", timeout);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.stop()
+    }
+
+    /// Stops the server at once, the pacts are already written as they are recorded
     pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
         if !self.is_running() {
             debug!("Server is not running");
             return Ok(());
         }
+        self.stop_front();
+        self.stop_route_servers()?;
         match &self.server {
             WrappedServer::Httpserver(server) => {
                 debug!("Unblocking tiny-htpp server, sending unblock to the message queue");
@@ -112,13 +550,19 @@ impl PactServer {
                     debug!("Joining thread");
                     server_thread
                         .join()
-                        .expect("Could not join server thread")?;
+                        .map_err(|_| "Server thread panicked")??;
                     debug!("Thread joined");
                 }
                 Ok(())
             }
-            WrappedServer::Grpc => {
-                unimplemented!()
+            WrappedServer::Grpc(server) => {
+                server.stop();
+                if let Some(server_thread) = self.server_thread.take() {
+                    server_thread
+                        .join()
+                        .map_err(|_| "Server thread panicked")??;
+                }
+                Ok(())
             }
         }
     }
@@ -128,18 +572,59 @@ impl PactServer {
     }
 }
 
+/// Shuts down a server spawned as a task, dropping the handle shuts it down without waiting
+pub struct ShutdownHandle(oneshot::Sender<Duration>);
+
+impl ShutdownHandle {
+    /// Stops accepting connections and serves the requests in flight for up to `timeout`,
+    /// the task of the server ends after that
+    pub fn shutdown(self, timeout: Duration) {
+        let _ = self.0.send(timeout);
+    }
+}
+
+/// Running server, stopped when dropped. Derefs to the server
+#[must_use = "the server stops when the guard is dropped"]
+pub struct ServerGuard<'a> {
+    server: &'a mut PactServer,
+}
+
+impl Deref for ServerGuard<'_> {
+    type Target = PactServer;
+
+    fn deref(&self) -> &PactServer {
+        self.server
+    }
+}
+
+impl DerefMut for ServerGuard<'_> {
+    fn deref_mut(&mut self) -> &mut PactServer {
+        self.server
+    }
+}
+
+impl Drop for ServerGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.server.stop() {
+            debug!("Error stopping the server: {}", e);
+        }
+    }
+}
+
 #[cfg_attr(feature = "flame_it", flame)]
 fn spawn_thread_with_http_server(
     pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
     server: Arc<Server>,
+    routing: Routing,
 ) -> Option<JoinHandle<Result<(), String>>> {
-    Some(thread::spawn(|| {
+    Some(thread::spawn(move || {
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async move {
-                match run_http_server(server.clone().as_ref(), pacts).await {
+                match run_http_server(server, pacts, config, &routing).await {
                     Ok(_) => Ok(()),
                     Err(e) => {
                         debug!("Error: {}", e);
@@ -151,65 +636,433 @@ fn spawn_thread_with_http_server(
 }
 
 #[cfg_attr(feature = "flame_it", flame)]
-async fn run_http_server(server: &Server, pacts: Arc<Pacts>) -> Result<(), Box<dyn Error>> {
-    for mut request in server.incoming_requests() {
-        debug!("Got request: {:?}", request);
-        let response = get_response(&pacts, &mut request).await?;
-        debug!("Sending back response");
-        #[cfg(feature = "flame_it")]
-        dump_flame_file(request.url());
-        request.respond(response)?;
+async fn run_http_server(
+    server: Arc<Server>,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+    routing: &Routing,
+) -> Result<(), Box<dyn Error>> {
+    // received off the runtime, the tasks of the requests, e.g. the recording of a relayed
+    // stream, go on while the server waits for the next request
+    loop {
+        let request = {
+            let server = server.clone();
+            match task::spawn_blocking(move || server.recv()).await? {
+                Ok(request) => request,
+                Err(_) => break,
+            }
+        };
+        let in_flight = received_in_flight(&request, &config);
+        // a failing request is answered with an error, the server goes on with the next ones
+        if let Err(e) = serve_request(request, in_flight, &pacts, &config, routing).await {
+            debug!("Error serving request: {}", e);
+        }
     }
     Ok(())
 }
+
+/// Serves the requests as tasks of the current runtime until the shutdown is received.
+/// The connections accepted in the meantime are served for up to the timeout of the shutdown
+async fn run_http_server_task(
+    server: Arc<Server>,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+    mut shutdown: oneshot::Receiver<Duration>,
+    front: Option<Arc<Front>>,
+) {
+    let receive = |server: &Arc<Server>| {
+        let server = server.clone();
+        tokio::task::spawn_blocking(move || server.recv())
+    };
+    let mut next_request = receive(&server);
+    let mut drain_until = None;
+    loop {
+        tokio::select! {
+            timeout = &mut shutdown, if drain_until.is_none() => {
+                // a dropped handle shuts the server down without waiting
+                drain_until = Some(Instant::now() + timeout.unwrap_or_default());
+                if let Some(front) = &front {
+                    front.stop();
+                }
+            }
+            request = &mut next_request => {
+                let request = match request {
+                    Ok(Ok(request)) => request,
+                    _ => break,
+                };
+                let in_flight = received_in_flight(&request, &config);
+                let pacts = pacts.clone();
+                let config = config.clone();
+                tokio::spawn(
+                    async move {
+                        let routing = Routing::PathEncoded;
+                        if let Err(e) =
+                            serve_request(request, in_flight, &pacts, &config, &routing).await
+                        {
+                            debug!("Error serving request: {}", e);
+                        }
+                    }
+                    .in_current_span(),
+                );
+                next_request = receive(&server);
+            }
+            _ = tokio::time::sleep(Duration::from_millis(10)), if drain_until.is_some() => {
+                let timed_out = drain_until.is_some_and(|until| Instant::now() >= until);
+                if config.in_flight.is_idle() || timed_out {
+                    break;
+                }
+            }
+        }
+    }
+    server.unblock();
+    let _ = next_request.await;
+}
+
+/// Counts the request as in flight, the requests passed through by the front were counted
+/// since they were forwarded to the http server
+fn received_in_flight(request: &Request, config: &ProxyConfig) -> InFlightRequest {
+    let remote_addr = request.remote_addr().copied();
+    config
+        .passed_connections
+        .received(remote_addr)
+        .unwrap_or_else(|| config.in_flight.enter())
+}
+
+#[cfg_attr(feature = "flame_it", flame)]
+async fn serve_request(
+    mut request: Request,
+    in_flight: InFlightRequest,
+    pacts: &Arc<Pacts>,
+    config: &ProxyConfig,
+    routing: &Routing,
+) -> Result<(), Box<dyn Error>> {
+    debug!("Got request: {:?}", request);
+    if request.method() == &Method::Connect {
+        debug!("CONNECT received but https interception is not enabled");
+        request.respond(Response::empty(405))?;
+        return Ok(());
+    }
+    let mut pact_request = match http_request_to_pact(&mut request, routing) {
+        Ok(pact_request) => pact_request,
+        Err(e) => {
+            request.respond(error_response(400, e.as_ref()))?;
+            return Ok(());
+        }
+    };
+    if config.forwarded_headers {
+        let host = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Host"))
+            .map(|header| header.value.to_string());
+        // the connections passed through by the front come from the front
+        let client = config
+            .passed_connections
+            .client(request.remote_addr().copied());
+        add_forwarded_headers(
+            &mut pact_request,
+            client.addr,
+            host.as_deref(),
+            client.proto,
+        );
+    }
+    let response = match get_response(pacts, config, &pact_request).await {
+        Ok(response) => response,
+        Err(e) => ClientResponse::Buffered(error_response(502, e.as_ref())),
+    };
+    debug!("Sending back response");
+    #[cfg(feature = "flame_it")]
+    dump_flame_file(request.url());
+    let response = match response {
+        // the client may be a task of the same runtime, it reads while the response is written
+        ClientResponse::Buffered(response) => {
+            tokio::task::spawn_blocking(move || request.respond(response)).await??;
+            return Ok(());
+        }
+        ClientResponse::Paced(response) => response,
+        ClientResponse::Relayed(response, recording) => {
+            tokio::spawn(recording);
+            response
+        }
+    };
+    let mut writer = request.into_writer();
+    // the server goes on with the next requests while the stream is sent
+    thread::spawn(move || {
+        if let Err(e) = response.write_to(&mut writer) {
+            debug!("Error sending the stream: {}", e);
+        }
+        drop(in_flight);
+    });
+    Ok(())
+}
+
+/// Response to a request that could not be served, e.g. 400 for an unsupported request
+/// or 502 when the upstream can't be reached
+pub(crate) fn error_response(status: u16, error: &dyn Error) -> ResponseBox {
+    debug!("Answering {} to a failed request: {}", status, error);
+    Response::from_string(error.to_string())
+        .with_status_code(status)
+        .boxed()
+}
+
 #[cfg_attr(feature = "flame_it", flame)]
-async fn get_response(
+pub(crate) async fn get_response(
     pacts: &Arc<Pacts>,
-    request: &mut tiny_http::Request,
-) -> Result<Response<Cursor<Vec<u8>>>, Box<dyn Error>> {
-    let pact_request = http_request_to_pact(request)?;
+    config: &ProxyConfig,
+    pact_request: &HttpRequest,
+) -> Result<ClientResponse, Box<dyn Error>> {
     debug!("pact_request: {pact_request:?}");
-    let consumer_provider = edit::get_consumer_provider(&pact_request)?;
+    let (session, pact_request) = session::split_session(pact_request)?;
+    let (provider_state, pact_request) = split_header(&pact_request, PROVIDER_STATE_HEADER);
+    let provider_state = provider_state.or_else(|| config.provider_state());
+    let pact_request = pact_request.as_ref();
+    let (consumer, provider) = edit::get_consumer_provider(pact_request, config.consumer())?;
+    let consumers = session::consumer_layers(&consumer, session.as_deref(), config.mode);
+    let description = edit::interaction_description(pact_request);
     debug!(
-        "Checking if pact exists for consumer: {} and provider: {}",
-        consumer_provider.0, consumer_provider.1
+        "Checking if pact exists for consumers: {:?} and provider: {}",
+        consumers, provider
     );
-    let response = match pacts.get_pact_response(
-        &consumer_provider.0,
-        &consumer_provider.1,
-        &pact_request.path,
-    ) {
-        Some(pact_response) => response_when_interaction_exists(&pact_response)?,
-        None => response_when_no_interaction(pacts, &pact_request, &consumer_provider).await?,
+    let recorded_response = match consumers.iter().find_map(|consumer| {
+        pacts.get_pact_response(
+            consumer,
+            &provider,
+            &description,
+            provider_state.as_deref(),
+            config.sequence_end(),
+        )
+    }) {
+        Some(recorded_response) => {
+            debug!("Match found");
+            recorded_response
+        }
+        None if config.mode == Mode::Replay => {
+            debug!("No interaction recorded for {}", description);
+            let message = format!("No interaction recorded for {description}");
+            let response = Response::from_string(message).with_status_code(404);
+            return Ok(ClientResponse::Buffered(response.boxed()));
+        }
+        None => {
+            let consumer_provider = (consumers[0].clone(), provider);
+            let provider_state = provider_state.as_deref();
+            return response_when_no_interaction(
+                pacts,
+                config,
+                pact_request,
+                &consumer_provider,
+                provider_state,
+            )
+            .await;
+        }
     };
-    Ok(response)
+    response_to_client(config, pact_request, &recorded_response)
 }
 
 #[cfg_attr(feature = "flame_it", flame)]
-fn response_when_interaction_exists(
+fn response_to_client(
+    config: &ProxyConfig,
+    pact_request: &HttpRequest,
+    recorded_response: &RecordedResponse,
+) -> Result<ClientResponse, Box<dyn Error>> {
+    debug!("pact_response: {:?}", recorded_response.response);
+    let accept_encoding = pact_request.lookup_header_value("accept-encoding");
+    // a body file is sent as it is unless its urls are rewritten or it is encoded
+    let (pact_response, body_file) = match &recorded_response.body_file {
+        Some(body_file)
+            if config.url_rewriter.is_some()
+                || response_encoding(&recorded_response.response, accept_encoding.as_deref())
+                    .is_some() =>
+        {
+            (
+                read_body_file(&recorded_response.response, body_file)?,
+                None,
+            )
+        }
+        body_file => (recorded_response.response.clone(), body_file.as_ref()),
+    };
+    let pact_response = client_pact_response(config, pact_request, &pact_response)?;
+    if let Some(body_file) = body_file {
+        let response = pact_response_to_streamed_http_response(&pact_response, body_file)?;
+        return Ok(ClientResponse::Buffered(response.boxed()));
+    }
+    if let Some(chunks) = recorded_chunks(&recorded_response.metadata) {
+        let response = PacedResponse::new(
+            &pact_response,
+            chunks,
+            config.stream_speed_up,
+            accept_encoding.as_deref(),
+            pact_request.method == "HEAD",
+        );
+        return Ok(ClientResponse::Paced(Box::new(response)));
+    }
+    let response = pact_response_to_http_response(&pact_response, accept_encoding.as_deref())?;
+    Ok(ClientResponse::Buffered(response.boxed()))
+}
+
+/// The response with the upstream urls rewritten and the `Via` header of the proxy
+fn client_pact_response(
+    config: &ProxyConfig,
+    pact_request: &HttpRequest,
     pact_response: &HttpResponse,
-) -> Result<Response<Cursor<Vec<u8>>>, Box<dyn Error>> {
-    debug!("Match found");
-    let response = pact_response_to_http_response(pact_response)?;
-    debug!("pact_response: {pact_response:?}");
-    Ok(response)
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let mut pact_response = match &config.url_rewriter {
+        Some(url_rewriter) => url_rewriter.rewrite(&pact_request.path, pact_response)?,
+        None => pact_response.clone(),
+    };
+    if let Some(via) = &config.via {
+        add_via(pact_response.headers.get_or_insert_with(HashMap::new), via);
+    }
+    Ok(pact_response)
 }
 
 #[cfg_attr(feature = "flame_it", flame)]
 async fn response_when_no_interaction(
     pacts: &Arc<Pacts>,
+    config: &ProxyConfig,
+    pact_request: &HttpRequest,
+    consumer_provider: &(String, String),
+    provider_state: Option<&str>,
+) -> Result<ClientResponse, Box<dyn Error>> {
+    let (head, mut stream) = match web::get_response_from_web(pact_request, config).await? {
+        UpstreamResponse::Complete(pact_response) => {
+            record_interaction(
+                pacts,
+                config,
+                pact_request,
+                consumer_provider,
+                provider_state,
+                &pact_response,
+                HashMap::new(),
+            )?;
+            let recorded_response = RecordedResponse {
+                response: pact_response,
+                body_file: None,
+                metadata: HashMap::new(),
+            };
+            return response_to_client(config, pact_request, &recorded_response);
+        }
+        UpstreamResponse::Streaming(head, stream) => (head, stream),
+    };
+    // the client gets the chunks as they arrive, the stream is recorded once it ends
+    let (sender, receiver) = mpsc::channel();
+    let client_head = client_pact_response(config, pact_request, &head)?;
+    let response = PacedResponse::relay(&client_head, receiver, pact_request.method == "HEAD");
+    let (pacts, config, pact_request) = (pacts.clone(), config.clone(), pact_request.clone());
+    let consumer_provider = consumer_provider.clone();
+    let provider_state = provider_state.map(str::to_string);
+    let recording = async move {
+        let _in_flight = config.in_flight.enter();
+        let relayed = read_chunks(&mut stream, |chunk| {
+            let chunk = rewrite_chunk(&config, &pact_request, &head, chunk);
+            sender.send(chunk).is_ok()
+        })
+        .await;
+        let recorded = relayed.and_then(|relayed| {
+            let (body, mut chunks) = match relayed {
+                Some(relayed) => relayed,
+                None => {
+                    debug!("The client left, the cut off stream is not recorded");
+                    return Ok(());
+                }
+            };
+            // the chunks are replayed from the body as it is recorded
+            if let Some(encodings) = body_encodings(&head).filter(|_| !config.exact_bodies) {
+                chunks = decoded_chunks(&body, &chunks, &encodings)?;
+            }
+            let mut pact_response = head;
+            set_pact_response_body(&mut pact_response, body);
+            web::prepare_body_for_pact(&mut pact_response, &config)?;
+            record_interaction(
+                &pacts,
+                &config,
+                &pact_request,
+                &consumer_provider,
+                provider_state.as_deref(),
+                &pact_response,
+                chunks_metadata(&chunks),
+            )
+        });
+        if let Err(e) = recorded {
+            debug!("Error recording the stream: {}", e);
+        }
+        // the stream ends for the client once it is recorded
+        drop(sender);
+    };
+    Ok(ClientResponse::Relayed(
+        Box::new(response),
+        Box::pin(recording.in_current_span()),
+    ))
+}
+
+/// Chunk of a relayed stream with the upstream urls rewritten, the encoded streams are
+/// relayed as they are
+fn rewrite_chunk(
+    config: &ProxyConfig,
+    pact_request: &HttpRequest,
+    head: &HttpResponse,
+    chunk: Bytes,
+) -> Bytes {
+    let url_rewriter = match &config.url_rewriter {
+        Some(url_rewriter) if head.lookup_header_value("content-encoding").is_none() => {
+            url_rewriter
+        }
+        _ => return chunk,
+    };
+    let part = HttpResponse {
+        body: OptionalBody::Present(chunk.clone(), None, None),
+        ..head.clone()
+    };
+    url_rewriter
+        .rewrite(&pact_request.path, &part)
+        .ok()
+        .and_then(|part| part.body.value())
+        .unwrap_or(chunk)
+}
+
+/// Records a new interaction, the redaction and the hooks only edit the recording
+fn record_interaction(
+    pacts: &Arc<Pacts>,
+    config: &ProxyConfig,
     pact_request: &HttpRequest,
     consumer_provider: &(String, String),
-) -> Result<Response<Cursor<Vec<u8>>>, Box<dyn Error>> {
-    let (pact_response, response) = web::get_response_from_web(pact_request).await?;
+    provider_state: Option<&str>,
+    pact_response: &HttpResponse,
+    mut metadata: HashMap<String, Value>,
+) -> Result<(), Box<dyn Error>> {
+    let mut recorded_request = pact_request.clone();
+    let mut recorded_response = pact_response.clone();
+    config.before_recording(&mut recorded_request, &mut recorded_response);
+    if let Some(threshold) = config.spill_bodies_over {
+        if let Some(reference) = spill_body(&pacts.get_folder(), &mut recorded_response, threshold)?
+        {
+            metadata.extend(body_file_metadata(&reference));
+        }
+    }
     pacts.add_interaction(
-        &consumer_provider.0,
-        &consumer_provider.1,
-        pact_request,
-        &pact_response,
+        consumer_provider,
+        &edit::interaction_description(pact_request),
+        provider_state,
+        &recorded_request,
+        &recorded_response,
+        metadata,
     )?;
-    pacts.save_pact(&consumer_provider.0, &consumer_provider.1)?;
-    Ok(response)
+    pacts.save_pact(&consumer_provider.0, &consumer_provider.1)
+}
+
+fn make_pacts(
+    pacts_folder: &Path,
+    pacts: Option<HashMap<(String, String), V4Pact>>,
+) -> Result<Arc<Pacts>, Box<dyn Error>> {
+    let pacts = match pacts {
+        Some(pacts) => pacts,
+        None => edit::read_pacts(pacts_folder)?,
+    };
+    let interaction_index_map = make_interaction_index_map(&pacts);
+    Ok(Arc::new(Pacts::new(
+        Arc::new(Mutex::new(pacts)),
+        Arc::new(Mutex::new(interaction_index_map)),
+        pacts_folder.to_path_buf(),
+    )))
 }
 
 #[cfg_attr(feature = "flame_it", flame)]
@@ -218,17 +1071,36 @@ fn make_interaction_index_map(pacts: &HashMap<(String, String), V4Pact>) -> Inte
     for (consumer_provider, pact) in pacts.iter() {
         let mut index = HashMap::new();
         for (i, interaction) in pact.interactions.iter().enumerate() {
-            index.insert(interaction.description(), i as u16);
+            let provider_states = interaction
+                .provider_states()
+                .into_iter()
+                .map(|provider_state| provider_state.name)
+                .collect::<Vec<_>>();
+            let provider_state =
+                (!provider_states.is_empty()).then(|| provider_states.join(" and "));
+            let key = edit::interaction_key(&interaction.description(), provider_state.as_deref());
+            index.entry(key).or_insert_with(Vec::new).push(i as u16);
         }
         interaction_index.insert(consumer_provider.clone(), index);
     }
     interaction_index
 }
 
-fn make_http_server(port: String) -> Result<WrappedServer, Box<dyn Error>> {
-    let server = Server::http("localhost:".to_owned() + port.as_str());
+/// The http server only gets the connections passed through by the front listening on `addr`
+fn make_fronted_http_server(
+    addr: &ListenAddr,
+    interceptor: Option<Arc<Interceptor>>,
+) -> Result<(WrappedServer, Front), Box<dyn Error>> {
+    let server = bind_http_server(&("localhost:".to_owned() + ANY_PORT))?;
+    let backend_addr = server.server_addr().to_ip().ok_or("No backend address")?;
+    let front = Front::bind(addr, backend_addr, interceptor)?;
+    Ok((WrappedServer::Httpserver(server), front))
+}
+
+fn bind_http_server(addr: &str) -> Result<Arc<Server>, Box<dyn Error>> {
+    let server = Server::http(addr);
     match server {
-        Ok(server) => Ok(WrappedServer::Httpserver(Arc::new(server))),
+        Ok(server) => Ok(Arc::new(server)),
         Err(e) => {
             debug!("Error starting server: {}", e);
             Err("Could not start server".into())
@@ -239,8 +1111,9 @@ fn make_http_server(port: String) -> Result<WrappedServer, Box<dyn Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::UpstreamClient;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
     use crate::utils::set_hook_on_panic_or_signal;
-    use serde_json::Value;
     use std::path::PathBuf;
     use std::str::FromStr;
 
@@ -253,8 +1126,8 @@ mod tests {
         assert!(!test_pacts_folder.exists());
         let mut pact_server = PactServer::with_http_server(&test_pacts_folder, None, None).unwrap();
         let port = pact_server.port().unwrap();
-        pact_server.start_non_blocking().await.unwrap();
-        let test_urls = vec![[
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let test_urls = [[
             format!("http://localhost:{}/https/httpbin.org/get", port),
             format!("http://localhost:{}/https/httpbin.org/post", port),
         ]];
@@ -274,6 +1147,136 @@ mod tests {
         cleanup_pacts_folder()
     }
 
+    #[test]
+    fn test_integration_guard_stops_on_panic() {
+        let upstream = TestUpstream::start(|_| Response::from_string("recorded").boxed());
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/panic",
+            upstream.port
+        );
+        let test = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let _pact_server = pact_server.start_non_blocking().await.unwrap();
+                    let response = reqwest::get(url).await.unwrap();
+                    assert_eq!(response.text().await.unwrap(), "recorded");
+                    panic!("test failed while the server is running");
+                })
+        }));
+        assert!(test.is_err());
+        assert!(!pact_server.is_running());
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains("recorded"));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_shutdown_drains_requests() {
+        let upstream = TestUpstream::start(|_| {
+            thread::sleep(std::time::Duration::from_millis(300));
+            Response::from_string("slow").boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/slow",
+            upstream.port
+        );
+        let request = tokio::spawn(reqwest::get(url.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // queued by the http server while it serves the first request
+        let queued = tokio::spawn(reqwest::get(url + "/queued"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pact_server.shutdown(Duration::from_secs(5)).await.unwrap();
+        for request in [request, queued] {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.text().await.unwrap(), "slow");
+        }
+        assert!(!pact_server.is_running());
+        assert!(pacts_folder.join("consumer-127.0.0.1.json").exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_spawn_on_current_runtime() {
+        let large_body = "x".repeat(1 << 20);
+        let body = large_body.clone();
+        let upstream = TestUpstream::start(move |_| Response::from_string(body.as_str()).boxed());
+        let pacts_folder = test_pacts_folder();
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let recorded_threads = threads.clone();
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .on_record(move |_, _| {
+                recorded_threads
+                    .lock()
+                    .unwrap()
+                    .push(thread::current().id());
+            })
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let route = format!("0=http://127.0.0.1:{}", upstream.port);
+        let route_port = pact_server.add_route(route.parse().unwrap()).unwrap();
+        let (server_task, shutdown) = pact_server.spawn();
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/task",
+            upstream.port
+        );
+        for _ in 0..2 {
+            let response = reqwest::get(url.as_str()).await.unwrap();
+            assert_eq!(response.text().await.unwrap().len(), large_body.len());
+        }
+        let response = reqwest::get(format!("http://localhost:{route_port}/route"))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap().len(), large_body.len());
+        assert_eq!(upstream.hits(), 2);
+        // the requests of the http server and of the route are served by the runtime of the test
+        let test_thread = thread::current().id();
+        assert_eq!(*threads.lock().unwrap(), [test_thread, test_thread]);
+        shutdown.shutdown(Duration::from_secs(1));
+        server_task.await.unwrap().unwrap();
+        assert!(pacts_folder.join("consumer-127.0.0.1.json").exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_failed_requests_are_answered() {
+        let upstream = TestUpstream::start(|_| Response::from_string("served").boxed());
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::builder()
+            .pacts_folder(&pacts_folder)
+            .upstream_client(UpstreamClient {
+                attempts: 1,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let unsupported = reqwest::get(format!("http://localhost:{port}/no-scheme"))
+            .await
+            .unwrap();
+        assert_eq!(unsupported.status(), 400);
+        let unreachable = reqwest::get(format!("http://localhost:{port}/http/127.0.0.1:1/down"))
+            .await
+            .unwrap();
+        assert_eq!(unreachable.status(), 502);
+        let url = format!(
+            "http://localhost:{port}/http/127.0.0.1:{}/up",
+            upstream.port
+        );
+        let response = reqwest::get(url).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "served");
+        pact_server.stop().unwrap();
+    }
+
     fn cleanup_pacts_folder() {
         let pacts_folder = PathBuf::from_str(PACTS_FOLDER).unwrap();
         if pacts_folder.exists() {
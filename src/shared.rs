@@ -0,0 +1,101 @@
+use crate::listener::LocalAddr;
+use crate::server::{PactServer, Port};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use tracing::debug;
+
+/// Servers shared by the tests of the process, one per pacts folder
+static SHARED: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<PactServer>>>>> = OnceLock::new();
+
+/// Handle to a server shared by the whole process, see `PactServer::shared`
+#[derive(Clone)]
+pub struct SharedPactServer {
+    server: Arc<Mutex<PactServer>>,
+}
+
+impl PactServer {
+    /// Handle to the server of the process recording to `pacts_folder`. The server is
+    /// started by the first call and keeps serving until the process exits, so the tests
+    /// running in parallel share one port and one copy of the pacts. It is not stopped:
+    /// every interaction is written to the folder as it is recorded, before its response
+    /// reaches the client, so the exit of the process loses nothing
+    pub fn shared(pacts_folder: impl AsRef<Path>) -> Result<SharedPactServer, Box<dyn Error>> {
+        let pacts_folder = std::env::current_dir()?.join(pacts_folder);
+        let mut servers = shared_servers();
+        let server = match servers.get(&pacts_folder) {
+            Some(server) => server.clone(),
+            None => {
+                let mut server = PactServer::with_http_server(&pacts_folder, None, None)?;
+                server.start_threads();
+                debug!("Started shared pact server on {:?}", server.local_addr());
+                let server = Arc::new(Mutex::new(server));
+                servers.insert(pacts_folder, server.clone());
+                server
+            }
+        };
+        Ok(SharedPactServer { server })
+    }
+}
+
+impl SharedPactServer {
+    pub fn local_addr(&self) -> Result<LocalAddr, Box<dyn Error>> {
+        self.server().local_addr()
+    }
+
+    pub fn port(&self) -> Result<Port, Box<dyn Error>> {
+        self.server().port()
+    }
+
+    fn server(&self) -> MutexGuard<'_, PactServer> {
+        // a test panicking while it held the lock leaves the server usable
+        self.server
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn shared_servers() -> MutexGuard<'static, HashMap<PathBuf, Arc<Mutex<PactServer>>>> {
+    SHARED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_pacts_folder, TestUpstream};
+    use std::thread;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_shared_server_is_started_once() {
+        let upstream = TestUpstream::start(|_| Response::from_string("shared").boxed());
+        let pacts_folder = test_pacts_folder();
+        let ports = (0..4)
+            .map(|i| {
+                let pacts_folder = pacts_folder.to_path_buf();
+                let upstream_port = upstream.port;
+                thread::spawn(move || {
+                    let server = PactServer::shared(&pacts_folder).unwrap();
+                    let port = server.port().unwrap();
+                    let url = format!("http://localhost:{port}/http/127.0.0.1:{upstream_port}/{i}");
+                    let body = reqwest::blocking::get(url).unwrap().text().unwrap();
+                    assert_eq!(body, "shared");
+                    port
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(ports.iter().all(|port| *port == ports[0]));
+        // written as it was recorded, while the server keeps serving
+        assert!(pacts_folder.join("consumer-127.0.0.1.json").exists());
+        let server = PactServer::shared(&pacts_folder).unwrap();
+        assert_eq!(server.port().unwrap(), ports[0]);
+        drop(server);
+    }
+}
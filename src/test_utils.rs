@@ -0,0 +1,130 @@
+use crate::builder::PactServerBuilder;
+use crate::config::Mode;
+use crate::server::{PactServer, Port};
+use std::future::Future;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{ResponseBox, Server};
+
+/// Upstream http server for tests, counts the requests that reach it
+pub struct TestUpstream {
+    pub port: u16,
+    server: Arc<Server>,
+    hits: Arc<AtomicUsize>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestUpstream {
+    pub fn start<F>(handler: F) -> TestUpstream
+    where
+        F: Fn(&mut tiny_http::Request) -> ResponseBox + Send + 'static,
+    {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let server = server.clone();
+            let hits = hits.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    let response = handler(&mut request);
+                    let _ = request.respond(response);
+                }
+            })
+        };
+        TestUpstream {
+            port,
+            server,
+            hits,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Url of the path of the upstream through the proxy listening on the port
+    pub fn url(&self, port: &str, path: &str) -> String {
+        format!("http://localhost:{port}/http/127.0.0.1:{}{path}", self.port)
+    }
+}
+
+impl Drop for TestUpstream {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Unique pacts folder for a test so that tests can run in parallel
+pub fn test_pacts_folder() -> TestPactsFolder {
+    TestPactsFolder(std::env::temp_dir().join(format!("pact-proxy-rs-{}", rand::random::<u64>())))
+}
+
+/// Removes the pacts folder and the ones named after it, e.g. `<folder>.tls`, when the test
+/// ends, also when one of its assertions fails
+pub struct TestPactsFolder(PathBuf);
+
+impl Deref for TestPactsFolder {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestPactsFolder {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&TestPactsFolder> for PathBuf {
+    fn from(folder: &TestPactsFolder) -> PathBuf {
+        folder.0.clone()
+    }
+}
+
+impl Drop for TestPactsFolder {
+    fn drop(&mut self) {
+        let (Some(parent), Some(name)) = (self.0.parent(), self.0.file_name()) else {
+            return;
+        };
+        let sibling_prefix = format!("{}.", name.to_string_lossy());
+        for entry in std::fs::read_dir(parent).into_iter().flatten().flatten() {
+            let file_name = entry.file_name();
+            if file_name == name || file_name.to_string_lossy().starts_with(&sibling_prefix) {
+                let path = entry.path();
+                let _ = match path.is_dir() {
+                    true => std::fs::remove_dir_all(path),
+                    false => std::fs::remove_file(path),
+                };
+            }
+        }
+    }
+}
+
+/// Runs the test against a server recording into the pacts folder, then against a new
+/// server replaying the recorded pacts
+pub async fn record_then_replay<C, T, F>(pacts_folder: &Path, configure: C, test: T)
+where
+    C: Fn(PactServerBuilder) -> PactServerBuilder,
+    T: Fn(Port) -> F,
+    F: Future<Output = ()>,
+{
+    for mode in [Mode::Record, Mode::Replay] {
+        let builder = PactServer::builder().pacts_folder(pacts_folder).mode(mode);
+        let mut pact_server = configure(builder).build().unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        test(port).await;
+        pact_server.stop().unwrap();
+    }
+}
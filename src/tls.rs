@@ -0,0 +1,246 @@
+use crate::config::{PassedClient, ProxyConfig};
+use crate::http1::{self, ClientWriter, MAX_HEADERS};
+use crate::listener::Stream;
+use crate::pacts::Pacts;
+use crate::utils;
+use crate::websocket::{self, UpgradeRequest};
+use rcgen::{Certificate, CertificateParams, DnType, SanType};
+use rustls::ServerConfig;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const COMMON_NAME: &str = "pact-proxy-rs";
+
+/// Certificate the proxy serves https with
+#[derive(Clone, Debug)]
+pub enum TlsCertificate {
+    /// Certificate for localhost, generated into the folder if it does not exist yet.
+    /// The clients trust the `cert.pem` of the folder
+    SelfSigned(PathBuf),
+    /// Pem files of the certificate chain and of its private key
+    Files { cert: PathBuf, key: PathBuf },
+}
+
+impl TlsCertificate {
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+        let (cert_path, key_path) = match self {
+            TlsCertificate::SelfSigned(folder) => generate_if_not_exists(folder)?,
+            TlsCertificate::Files { cert, key } => (cert.clone(), key.clone()),
+        };
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return Err(format!("No certificate in {}", cert_path.display()).into());
+        }
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, read_private_key(&key_path)?)?;
+        // the http server behind the front only speaks http/1.1
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(server_config))
+    }
+
+    /// Path of the certificate the clients have to trust
+    pub fn cert_path(&self) -> PathBuf {
+        match self {
+            TlsCertificate::SelfSigned(folder) => folder.join(CERT_FILE),
+            TlsCertificate::Files { cert, .. } => cert.clone(),
+        }
+    }
+}
+
+fn generate_if_not_exists(folder: &Path) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    utils::create_folder_if_not_exists(folder)?;
+    let cert_path = folder.join(CERT_FILE);
+    let key_path = folder.join(KEY_FILE);
+    if !(cert_path.exists() && key_path.exists()) {
+        debug!("Generating self-signed certificate into {:?}", cert_path);
+        let mut params = CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, COMMON_NAME);
+        params.subject_alt_names = vec![
+            SanType::DnsName("localhost".to_string()),
+            SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ];
+        let certificate = Certificate::from_params(params)?;
+        std::fs::write(&cert_path, certificate.serialize_pem()?)?;
+        utils::write_private_key(&key_path, &certificate.serialize_private_key_pem())?;
+    }
+    Ok((cert_path, key_path))
+}
+
+fn read_private_key(key_path: &Path) -> Result<rustls::PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(key_path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("No private key in {}", key_path.display()).into())
+}
+
+/// Terminates tls of a connection of the front. Websocket upgrades are served here,
+/// every other request is passed through to the http server
+pub fn serve_connection(
+    acceptor: &TlsAcceptor,
+    stream: Stream,
+    backend_addr: SocketAddr,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let client_addr = stream.peer_addr();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let tls_stream = runtime.block_on(async { acceptor.accept(stream.into_async()?).await })?;
+    let (reader, writer) = tokio::io::split(tls_stream);
+    // the requests are framed like the ones of the plain connections
+    let mut reader = BufReader::new(Blocking {
+        io: reader,
+        runtime: &runtime,
+    });
+    let mut writer = Blocking {
+        io: writer,
+        runtime: &runtime,
+    };
+    let mut head = match http1::read_head(&mut reader)? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        parsed.parse(&head)?;
+        if websocket::is_upgrade(parsed.headers) {
+            let request = UpgradeRequest {
+                target: parsed.path.ok_or("request should have path")?,
+                headers: parsed.headers,
+                read_ahead: reader.buffer().to_vec(),
+                client_addr,
+                proto: "https",
+            };
+            let stream = reader.into_inner().io.unsplit(writer.io);
+            return runtime.block_on(websocket::serve_async_upgrade(
+                request, stream, pacts, config,
+            ));
+        }
+        let client = PassedClient {
+            addr: client_addr,
+            proto: "https",
+        };
+        head = match http1::pass_through(
+            head,
+            &mut reader,
+            &mut writer,
+            client,
+            backend_addr,
+            &config,
+            |request| websocket::is_upgrade(request.headers),
+        )? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+    }
+}
+
+/// Half of a tls connection read or written with blocking calls that the runtime drives,
+/// the runtime can be blocked on from the reading and the writing thread at once
+struct Blocking<'r, T> {
+    io: T,
+    runtime: &'r Runtime,
+}
+
+impl<T: AsyncRead + Unpin> Read for Blocking<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.io.read(buf))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Write for Blocking<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.io.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.block_on(self.io.flush())
+    }
+}
+
+impl<T: AsyncWrite + Unpin + Send> ClientWriter for Blocking<'_, T> {
+    fn shutdown_write(&mut self) {
+        let _ = self.runtime.block_on(self.io.shutdown());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::test_pacts_folder;
+    use crate::test_utils::TestUpstream;
+    use tiny_http::Response;
+
+    #[test]
+    fn test_self_signed_certificate_is_generated_once() {
+        let folder = test_pacts_folder();
+        let certificate = TlsCertificate::SelfSigned(folder.to_path_buf());
+        certificate.server_config().unwrap();
+        let pem = std::fs::read_to_string(certificate.cert_path()).unwrap();
+        certificate.server_config().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(certificate.cert_path()).unwrap(),
+            pem
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = std::fs::metadata(folder.join(KEY_FILE)).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_serve_tls() {
+        let upstream = TestUpstream::start(|_| Response::from_string("over tls").boxed());
+        let pacts_folder = test_pacts_folder();
+        let certificate = TlsCertificate::SelfSigned(pacts_folder.with_extension("tls"));
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        pact_server.serve_tls(&certificate).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let pem = std::fs::read(certificate.cert_path()).unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&pem).unwrap())
+            .build()
+            .unwrap();
+        let url = format!(
+            "https://localhost:{port}/http/127.0.0.1:{}/secure",
+            upstream.port
+        );
+        for _ in 0..2 {
+            let response = client.get(url.as_str()).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "over tls");
+        }
+        assert_eq!(upstream.hits(), 1);
+        pact_server.stop().unwrap();
+    }
+}
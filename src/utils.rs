@@ -8,11 +8,12 @@ use signal_hook::{
     iterator::Signals,
 };
 use std::error::Error;
+use std::io;
 #[cfg(test)]
 use std::io::Write;
 use std::path::Path;
 #[cfg(test)]
-use std::{io, thread};
+use std::thread;
 
 pub fn create_folder_if_not_exists(folder_path: &Path) -> Result<(), Box<dyn Error>> {
     if !folder_path.exists() {
@@ -21,6 +22,29 @@ pub fn create_folder_if_not_exists(folder_path: &Path) -> Result<(), Box<dyn Err
     Ok(())
 }
 
+/// Writes a sibling file and renames it over `path`, readers never see a partial file
+pub fn write_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn Error>> {
+    let mut file_name = path
+        .file_name()
+        .ok_or("Path should have a file name")?
+        .to_owned();
+    file_name.push(".tmp");
+    let tmp_path = path.with_file_name(file_name);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Writes a private key that only its owner can read
+pub fn write_private_key(path: &Path, pem: &str) -> io::Result<()> {
+    use std::io::Write as _;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
 #[cfg(test)]
 pub fn set_hook_on_panic_or_signal<F: Fn() + Sync + Send + 'static + Clone>(
     hook: F,
@@ -28,7 +52,7 @@ pub fn set_hook_on_panic_or_signal<F: Fn() + Sync + Send + 'static + Clone>(
     let hook_cloned = hook.clone();
     thread::spawn(move || {
         let mut signals = Signals::new([SIGINT, SIGTERM])?;
-        for sig in signals.forever() {
+        if let Some(sig) = signals.forever().next() {
             hook_cloned.call(());
             // flush stdout and stderr of not only this thread but all threads in the process
             io::stdout().flush().unwrap();
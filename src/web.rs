@@ -1,6 +1,8 @@
+use crate::config::ProxyConfig;
+use crate::pact::encoding::decode_body;
+use crate::pact::headers::strip_hop_by_hop;
 use crate::pact::pact_to_request::copy_pact_headers_to_request;
-use crate::pact::pact_to_response::pact_response_to_http_response;
-use crate::pact::response_to_pact::reqwest_response_to_pact;
+use crate::pact::response_to_pact::{reqwest_response_to_pact, sniff_body_content_type};
 use bytes::Bytes;
 #[cfg(feature = "flame_it")]
 use flame as f;
@@ -8,22 +10,72 @@ use flame as f;
 use flamer::flame;
 use http::Method;
 use pact_models::bodies::OptionalBody;
-use pact_models::content_types::ContentType;
-use pact_models::v4::http_parts::{HttpRequest, HttpResponse};
-use reqwest::{Client, RequestBuilder};
+use pact_models::content_types::{ContentType, ContentTypeHint};
+use pact_models::v4::http_parts::{body_from_json, HttpRequest, HttpResponse};
+use reqwest::{redirect, Client, RequestBuilder, Response};
+use serde_json::json;
 use std::error::Error;
-use std::io::Cursor;
-use tiny_http::Response;
 use tracing::debug;
 
+/// Response of the upstream
+pub enum UpstreamResponse {
+    Complete(HttpResponse),
+    /// Status and headers of a streaming response, the body is still to be read
+    /// from the upstream response, see `read_chunks`
+    Streaming(HttpResponse, Box<Response>),
+}
+
 #[cfg_attr(feature = "flame_it", flame)]
 pub async fn get_response_from_web(
     pact_request: &HttpRequest,
-) -> Result<(HttpResponse, Response<Cursor<Vec<u8>>>), Box<dyn Error>> {
-    let mut pact_response = forward_get_request(pact_request).await?;
-    adjust_body_and_content_length(&mut pact_response)?;
-    let response = pact_response_to_http_response(&pact_response)?;
-    Ok((pact_response, response))
+    config: &ProxyConfig,
+) -> Result<UpstreamResponse, Box<dyn Error>> {
+    let (mut pact_response, stream) = forward_request(pact_request, config).await?;
+    if let Some(headers) = pact_response.headers.as_mut() {
+        strip_hop_by_hop(headers);
+    }
+    if let Some(stream) = stream {
+        return Ok(UpstreamResponse::Streaming(pact_response, Box::new(stream)));
+    }
+    prepare_body_for_pact(&mut pact_response, config)?;
+    Ok(UpstreamResponse::Complete(pact_response))
+}
+
+/// Decodes and normalizes the body of an upstream response, unless the exact bytes are kept:
+/// then a compressed body is recorded as it was received, with its `Content-Encoding`
+pub fn prepare_body_for_pact(
+    pact_response: &mut HttpResponse,
+    config: &ProxyConfig,
+) -> Result<(), Box<dyn Error>> {
+    if !config.exact_bodies {
+        decode_body(pact_response)?;
+    }
+    sniff_body_content_type(pact_response);
+    match config.exact_bodies {
+        true => keep_exact_body(pact_response),
+        false => adjust_body_and_content_length(pact_response)?,
+    }
+    Ok(())
+}
+
+/// Stores the body as base64 when the pact serialization would change its bytes,
+/// e.g. the whitespace, key order or number formatting of json.
+/// The content type header of the response is kept
+fn keep_exact_body(pact_response: &mut HttpResponse) {
+    let bytes = match &pact_response.body {
+        OptionalBody::Present(bytes, ..) => bytes.clone(),
+        _ => return,
+    };
+    let serialized = json!({ "body": pact_response.body.to_v4_json() });
+    if body_from_json(&serialized, "body", &None).value() == Some(bytes.clone()) {
+        return;
+    }
+    debug!("Body is stored base64 encoded to keep its bytes");
+    pact_response.body = OptionalBody::Present(
+        bytes,
+        Some(ContentType::parse("application/octet-stream").unwrap()),
+        Some(ContentTypeHint::BINARY),
+    );
 }
 
 fn adjust_body_and_content_length(pact_response: &mut HttpResponse) -> Result<(), Box<dyn Error>> {
@@ -77,34 +129,225 @@ fn adjust_body_to_pact_serialization(
             return Ok(());
         }
     };
-    let json_body = serde_json::from_slice::<serde_json::Value>(body)?;
+    let json_body = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json_body) => json_body,
+        Err(e) => {
+            debug!("Body is not json, it is kept as it is: {}", e);
+            return Ok(());
+        }
+    };
     let pretty_json_body = serde_json::to_string(&json_body)?;
     let pretty_json_body_bytes = Bytes::from(pretty_json_body);
     pact_response.body = OptionalBody::Present(pretty_json_body_bytes, Some(content_type), None);
     Ok(())
 }
 
-async fn forward_get_request(request: &HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
-    let mut response = None;
-    for _ in 0..5 {
-        let reqwest_request = build_request(request)?;
-        response = Some(reqwest_request.send().await?);
-        if let Some(ref res) = response {
-            if res.status().is_success() {
-                return reqwest_response_to_pact(response.unwrap()).await;
+/// Sends the request upstream. An idempotent request is sent again while the upstream
+/// fails or answers with an error, the others only when they could not connect.
+/// The response of the last attempt is passed on whatever its status
+async fn forward_request(
+    request: &HttpRequest,
+    config: &ProxyConfig,
+) -> Result<(HttpResponse, Option<Response>), Box<dyn Error>> {
+    let idempotent = Method::from_bytes(request.method.as_bytes())?.is_idempotent();
+    let mut attempt = 1;
+    loop {
+        let last_attempt = attempt >= config.upstream_client.attempts;
+        let reqwest_request = build_request(request, config)?;
+        match reqwest_request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                // each hop of a redirect is an interaction of its own
+                let accepted =
+                    status.is_success() || (config.record_redirects && status.is_redirection());
+                if accepted || !idempotent || last_attempt {
+                    return reqwest_response_to_pact(response).await;
+                }
+                debug!("Upstream answered {} on attempt {}", status, attempt);
+            }
+            Err(e) if !last_attempt && (idempotent || e.is_connect()) => {
+                debug!("Upstream failed on attempt {}: {}", attempt, e);
             }
+            Err(e) => return Err(e.into()),
         }
-        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        attempt += 1;
+        tokio::time::sleep(config.upstream_client.retry_delay).await;
     }
-    debug!("received response: {:?}", response);
-    Err("Could not get correct response from server".into())
 }
 
-fn build_request(pact_request: &HttpRequest) -> Result<RequestBuilder, Box<dyn Error>> {
-    let client = Client::new();
+fn build_request(
+    pact_request: &HttpRequest,
+    config: &ProxyConfig,
+) -> Result<RequestBuilder, Box<dyn Error>> {
+    // the bodies are decoded by the proxy, see `decode_body`
+    let mut client = Client::builder()
+        .no_gzip()
+        .no_deflate()
+        .danger_accept_invalid_certs(config.upstream_client.accept_invalid_certs);
+    if config.record_redirects {
+        client = client.redirect(redirect::Policy::none());
+    }
+    if let Some(timeout) = config.upstream_client.timeout {
+        client = client.timeout(timeout);
+    }
+    let client = client.build()?;
     let url = pact_request.path.clone();
     let method = pact_request.method.clone();
     let reqwest_request = client.request(Method::from_bytes(method.as_bytes())?, url);
-    let reqwest_request = copy_pact_headers_to_request(pact_request, reqwest_request)?;
+    let mut reqwest_request = copy_pact_headers_to_request(pact_request, reqwest_request)?;
+    if let OptionalBody::Present(body, ..) = &pact_request.body {
+        reqwest_request = reqwest_request.body(body.clone());
+    }
+    if let Some(via) = &config.via {
+        reqwest_request = reqwest_request.header("via", "1.1 ".to_owned() + via);
+    }
     Ok(reqwest_request)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::PactServerBuilder;
+    use crate::config::UpstreamClient;
+    use crate::pact::encoding::Encoding;
+    use crate::server::PactServer;
+    use crate::test_utils::{record_then_replay, test_pacts_folder, TestUpstream};
+    use std::time::Duration;
+    use tiny_http::Response;
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_error_responses_are_passed_on() {
+        let upstream = TestUpstream::start(|request| match request.url() {
+            "/orders" => Response::from_string("rejected")
+                .with_status_code(400)
+                .boxed(),
+            _ => Response::from_string("unavailable")
+                .with_status_code(503)
+                .boxed(),
+        });
+        let pacts_folder = test_pacts_folder();
+        let upstream = &upstream;
+        let retries = |builder: PactServerBuilder| {
+            builder.upstream_client(UpstreamClient {
+                attempts: 3,
+                retry_delay: Duration::from_millis(10),
+                ..Default::default()
+            })
+        };
+        record_then_replay(&pacts_folder, retries, |port| async move {
+            let client = reqwest::Client::new();
+            let response = client
+                .post(upstream.url(&port, "/orders"))
+                .body("{}")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 400);
+            assert_eq!(response.text().await.unwrap(), "rejected");
+            let response = client
+                .get(upstream.url(&port, "/status"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 503);
+            assert_eq!(response.text().await.unwrap(), "unavailable");
+        })
+        .await;
+        // the post is sent once, the get is sent again until the attempts run out
+        assert_eq!(upstream.hits(), 1 + 3);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_record_redirects() {
+        let upstream = TestUpstream::start(|request| match request.url() {
+            "/login" => Response::empty(302)
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Location"[..], &b"/home"[..]).unwrap(),
+                )
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Set-Cookie"[..], &b"session=1"[..]).unwrap(),
+                )
+                .boxed(),
+            _ => Response::from_string("home").boxed(),
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        pact_server.record_redirects();
+        pact_server.rewrite_upstream_urls().unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let proxy_prefix = format!("http://localhost:{port}/http/127.0.0.1:{}", upstream.port);
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let response = client
+                .get(proxy_prefix.clone() + "/login")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 302);
+            assert_eq!(response.headers()["set-cookie"], "session=1");
+            let location = response.headers()["location"].to_str().unwrap().to_string();
+            assert_eq!(location, proxy_prefix.clone() + "/home");
+            let response = client.get(location).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "home");
+        }
+        assert_eq!(upstream.hits(), 2);
+        pact_server.stop().unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_exact_bodies() {
+        const BODY: &str = "{\"b\": 1.10,\n \"a\": 12345678901234567890123}";
+        let upstream = TestUpstream::start(|request| {
+            let response = match Encoding::from_name(request.url().trim_start_matches('/')) {
+                Some(encoding) => Response::from_data(encoding.encode(BODY.as_bytes()).unwrap())
+                    .with_header(
+                        tiny_http::Header::from_bytes(
+                            &b"Content-Encoding"[..],
+                            encoding.name().as_bytes(),
+                        )
+                        .unwrap(),
+                    ),
+                None => Response::from_string(BODY),
+            };
+            response
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .unwrap(),
+                )
+                .boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let upstream = &upstream;
+        record_then_replay(
+            &pacts_folder,
+            |builder| builder.keep_exact_bodies(),
+            |port| async move {
+                let response = reqwest::get(upstream.url(&port, "/signed")).await.unwrap();
+                assert_eq!(response.headers()["content-type"], "application/json");
+                assert_eq!(response.text().await.unwrap(), BODY);
+                // a compressed body is kept as it was received
+                let client = reqwest::Client::builder()
+                    .no_gzip()
+                    .no_deflate()
+                    .build()
+                    .unwrap();
+                for encoding in [Encoding::Gzip, Encoding::Deflate] {
+                    let response = client
+                        .get(upstream.url(&port, &format!("/{}", encoding.name())))
+                        .header("Accept-Encoding", encoding.name())
+                        .send()
+                        .await
+                        .unwrap();
+                    assert_eq!(response.headers()["content-encoding"], encoding.name());
+                    let body = response.bytes().await.unwrap();
+                    assert_eq!(encoding.decode(&body).unwrap(), BODY.as_bytes());
+                }
+            },
+        )
+        .await;
+        assert_eq!(upstream.hits(), 3);
+    }
+}
@@ -0,0 +1,756 @@
+use crate::config::{Mode, ProxyConfig};
+use crate::listener::Stream;
+use crate::pact::edit;
+use crate::pact::headers::{add_forwarded_headers, add_via, strip_hop_by_hop};
+use crate::pact::session;
+use crate::pacts::{Pacts, RecordedMessage};
+use crate::routing::Routing;
+#[cfg(feature = "flame_it")]
+use flamer::flame;
+use futures_util::{SinkExt, StreamExt};
+use pact_models::bodies::OptionalBody;
+use pact_models::content_types::{ContentType, ContentTypeHint};
+use pact_models::v4::http_parts::HttpRequest;
+use pact_models::v4::message_parts::MessageContents;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
+use url::Url;
+
+const WEBSOCKET: &str = "websocket";
+const DIRECTION: &str = "direction";
+const SEQUENCE: &str = "sequence";
+const OFFSET_MILLIS: &str = "offsetMillis";
+/// Headers of the handshake between the client and the proxy, not passed on
+const HANDSHAKE_HEADERS: [&str; 5] = [
+    "host",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-accept",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    /// Sent by the client to the upstream
+    Client,
+    /// Sent by the upstream to the client
+    Server,
+}
+
+impl Direction {
+    fn name(&self) -> &'static str {
+        match self {
+            Direction::Client => "client",
+            Direction::Server => "server",
+        }
+    }
+}
+
+/// Data frame of a connection, `offset` is the time since the connection was opened
+#[derive(Debug)]
+struct Frame {
+    direction: Direction,
+    offset: Duration,
+    message: Message,
+}
+
+/// Whether the request asks to upgrade the connection to a websocket
+pub fn is_upgrade(headers: &[httparse::Header]) -> bool {
+    headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("upgrade")
+            && String::from_utf8_lossy(header.value).eq_ignore_ascii_case("websocket")
+    })
+}
+
+/// Upgrade request read by the front
+pub struct UpgradeRequest<'a> {
+    /// `/<scheme>/<host>/<path>` of the upstream
+    pub target: &'a str,
+    pub headers: &'a [httparse::Header<'a>],
+    /// Bytes read past the request, the first frames of the client
+    pub read_ahead: Vec<u8>,
+    pub client_addr: Option<SocketAddr>,
+    /// Scheme the client connected to the proxy with, `http` or `https`
+    pub proto: &'static str,
+}
+
+/// Serves a websocket upgrade on a plain connection of the front
+#[cfg_attr(feature = "flame_it", flame)]
+pub fn serve_upgrade(
+    target: &str,
+    headers: &[httparse::Header],
+    reader: BufReader<Stream>,
+    stream: Stream,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let request = UpgradeRequest {
+        target,
+        headers,
+        read_ahead: reader.buffer().to_vec(),
+        client_addr: stream.peer_addr(),
+        proto: "http",
+    };
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let stream = stream.into_async()?;
+            serve_async_upgrade(request, stream, pacts, config).await
+        })
+}
+
+/// Serves a websocket upgrade of the `/<scheme>/<host>/<path>` target.
+/// The frames of a new url are relayed to the upstream and recorded when the connection
+/// closes, a recorded url is replayed without the upstream
+pub async fn serve_async_upgrade<C>(
+    request: UpgradeRequest<'_>,
+    mut stream: C,
+    pacts: Arc<Pacts>,
+    config: Arc<ProxyConfig>,
+) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let _in_flight = config.in_flight.enter();
+    let headers = request.headers;
+    let (prefix_session, target) = match session::strip_session_prefix(request.target) {
+        Some((session, target)) => (Some(session), target),
+        None => (None, request.target),
+    };
+    let url = websocket_url(target)?;
+    let mut upstream_request = HttpRequest {
+        path: url.to_string(),
+        headers: Some(upstream_headers(headers)),
+        ..Default::default()
+    };
+    if let Some(session) = prefix_session {
+        session::add_session_header(&mut upstream_request, session);
+    }
+    let (session, upstream_request) = session::split_session(&upstream_request)?;
+    let mut upstream_request = upstream_request.into_owned();
+    let (consumer, provider) = edit::get_consumer_provider(&upstream_request, config.consumer())?;
+    // the frames are recorded to the first consumer, e.g. the one of the session
+    let consumers = session::consumer_layers(&consumer, session.as_deref(), config.mode);
+    let key = header_value(headers, "sec-websocket-key").ok_or("Sec-WebSocket-Key is missing")?;
+    let accept = derive_accept_key(key.as_bytes());
+    let frames = consumers
+        .iter()
+        .map(|consumer| recorded_frames(&pacts, consumer, &provider, &url))
+        .find(|frames| !frames.is_empty())
+        .unwrap_or_default();
+    if !frames.is_empty() {
+        debug!("Replaying {} frames of {}", frames.len(), url);
+        let protocol = header_value(headers, "sec-websocket-protocol")
+            .and_then(|protocols| protocols.split(',').next().map(|p| p.trim().to_string()));
+        write_switching_protocols(&mut stream, &accept, protocol.as_deref()).await?;
+        let client =
+            WebSocketStream::from_partially_read(stream, request.read_ahead, Role::Server, None)
+                .await;
+        return replay(client, &frames).await;
+    }
+    if config.mode == Mode::Replay {
+        debug!("No frames recorded for {}", url);
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+    if config.forwarded_headers {
+        let host = header_value(headers, "host");
+        add_forwarded_headers(
+            &mut upstream_request,
+            request.client_addr,
+            host.as_deref(),
+            request.proto,
+        );
+    }
+    if let Some(via) = &config.via {
+        add_via(
+            upstream_request.headers.get_or_insert_with(HashMap::new),
+            via,
+        );
+    }
+    let (upstream, upstream_response) =
+        match tokio_tungstenite::connect_async(upstream_client_request(&upstream_request)?).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                debug!("Error connecting to {}: {}", url, e);
+                stream
+                    .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                    .await?;
+                return Err(e.into());
+            }
+        };
+    let protocol = upstream_response
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|protocol| protocol.to_str().ok());
+    write_switching_protocols(&mut stream, &accept, protocol).await?;
+    let mut client =
+        WebSocketStream::from_partially_read(stream, request.read_ahead, Role::Server, None).await;
+    let frames = relay(&mut client, upstream).await;
+    debug!("Recording {} frames of {}", frames.len(), url);
+    if !frames.is_empty() {
+        let messages = frames
+            .iter()
+            .enumerate()
+            .map(|(sequence, frame)| recorded_message(&url, sequence, frame))
+            .collect();
+        pacts.add_messages(&consumers[0], &provider, messages)?;
+        pacts.save_pact(&consumers[0], &provider)?;
+    }
+    // the client sees the connection closed once the frames are recorded
+    let _ = client.close(None).await;
+    Ok(())
+}
+
+/// `/<scheme>/<host>/<path>` with the http schemes replaced by the websocket ones
+fn websocket_url(target: &str) -> Result<Url, Box<dyn Error>> {
+    let mut url = Routing::PathEncoded.forward_url(target, &[])?;
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        scheme => return Err(format!("Unsupported websocket scheme {scheme}").into()),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| "Could not set the websocket scheme")?;
+    Ok(url)
+}
+
+fn header_value(headers: &[httparse::Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| String::from_utf8_lossy(header.value).to_string())
+}
+
+/// Headers of the client passed on to the upstream handshake
+fn upstream_headers(headers: &[httparse::Header]) -> HashMap<String, Vec<String>> {
+    let mut upstream_headers: HashMap<String, Vec<String>> = HashMap::new();
+    for header in headers {
+        upstream_headers
+            .entry(header.name.to_lowercase())
+            .or_default()
+            .push(String::from_utf8_lossy(header.value).to_string());
+    }
+    strip_hop_by_hop(&mut upstream_headers);
+    upstream_headers.retain(|name, _| !HANDSHAKE_HEADERS.contains(&name.as_str()));
+    upstream_headers
+}
+
+fn upstream_client_request(
+    upstream_request: &HttpRequest,
+) -> Result<tokio_tungstenite::tungstenite::handshake::client::Request, Box<dyn Error>> {
+    let mut request = upstream_request.path.as_str().into_client_request()?;
+    for (name, values) in upstream_request.headers.iter().flatten() {
+        for value in values {
+            request.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+    }
+    Ok(request)
+}
+
+async fn write_switching_protocols<C: AsyncWrite + Unpin>(
+    stream: &mut C,
+    accept: &str,
+    protocol: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut response = "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: "
+        .to_owned()
+        + accept
+        + "\r\n";
+    if let Some(protocol) = protocol {
+        response += &("Sec-WebSocket-Protocol: ".to_owned() + protocol + "\r\n");
+    }
+    response += "\r\n";
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Passes the frames on in both directions until one side closes the connection.
+/// Pings are answered by each side, only the data frames are passed on and returned
+async fn relay<C, S>(
+    client: &mut WebSocketStream<C>,
+    mut upstream: WebSocketStream<S>,
+) -> Vec<Frame>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let mut frames = vec![];
+    loop {
+        let (direction, message) = tokio::select! {
+            message = client.next() => (Direction::Client, message),
+            message = upstream.next() => (Direction::Server, message),
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                debug!("Websocket error from the {}: {}", direction.name(), e);
+                break;
+            }
+            None => break,
+        };
+        let sent = match (&message, direction) {
+            (Message::Text(_) | Message::Binary(_), Direction::Client) => {
+                upstream.send(message.clone()).await
+            }
+            (Message::Text(_) | Message::Binary(_), Direction::Server) => {
+                client.send(message.clone()).await
+            }
+            (Message::Close(_), Direction::Client) => {
+                let _ = upstream.send(message).await;
+                break;
+            }
+            (Message::Close(_), Direction::Server) => break,
+            _ => continue,
+        };
+        frames.push(Frame {
+            direction,
+            offset: started.elapsed(),
+            message,
+        });
+        if let Err(e) = sent {
+            debug!("Websocket error passing on a frame: {}", e);
+            break;
+        }
+    }
+    frames
+}
+
+/// Sends the server frames of the recording in response to the matching client frames,
+/// with the delays they were recorded with
+async fn replay<C>(mut client: WebSocketStream<C>, frames: &[Frame]) -> Result<(), Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut position = send_server_frames(&mut client, frames, 0, Duration::ZERO).await?;
+    while let Some(message) = client.next().await {
+        let message = match message {
+            Ok(message @ (Message::Text(_) | Message::Binary(_))) => message,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                debug!("Websocket error from the client: {}", e);
+                break;
+            }
+        };
+        match frames[position..].iter().position(|frame| {
+            frame.direction == Direction::Client && same_message(&frame.message, &message)
+        }) {
+            Some(matched) => {
+                let matched = position + matched;
+                position =
+                    send_server_frames(&mut client, frames, matched + 1, frames[matched].offset)
+                        .await?;
+            }
+            None => debug!("No recorded frame matches {:?}", message),
+        }
+    }
+    let _ = client.close(None).await;
+    Ok(())
+}
+
+/// Sends the server frames from `position` up to the next client frame, whose position is
+/// returned. The delays are relative to the frame at `offset`, sent or received now
+async fn send_server_frames<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut WebSocketStream<C>,
+    frames: &[Frame],
+    mut position: usize,
+    offset: Duration,
+) -> Result<usize, Box<dyn Error>> {
+    let now = Instant::now();
+    while let Some(frame) = frames.get(position) {
+        if frame.direction == Direction::Client {
+            break;
+        }
+        tokio::time::sleep_until(now + frame.offset.saturating_sub(offset)).await;
+        client.send(frame.message.clone()).await?;
+        position += 1;
+    }
+    Ok(position)
+}
+
+/// Equal text, equal json whatever the formatting, or equal bytes
+fn same_message(recorded: &Message, received: &Message) -> bool {
+    match (recorded, received) {
+        (Message::Text(recorded), Message::Text(received)) => {
+            recorded == received
+                || matches!(
+                    (
+                        serde_json::from_str::<Value>(recorded),
+                        serde_json::from_str::<Value>(received),
+                    ),
+                    (Ok(recorded), Ok(received)) if recorded == received
+                )
+        }
+        (recorded, received) => recorded == received,
+    }
+}
+
+fn recorded_message(url: &Url, sequence: usize, frame: &Frame) -> (String, RecordedMessage) {
+    let description = format!("{} {} {}", url, frame.direction.name(), sequence);
+    let contents = match &frame.message {
+        Message::Text(text) => {
+            let content_type = match serde_json::from_str::<Value>(text) {
+                Ok(_) => "application/json",
+                Err(_) => "text/plain",
+            };
+            OptionalBody::Present(
+                text.clone().into_bytes().into(),
+                ContentType::parse(content_type).ok(),
+                None,
+            )
+        }
+        message => OptionalBody::Present(
+            message.clone().into_data().into(),
+            ContentType::parse("application/octet-stream").ok(),
+            Some(ContentTypeHint::BINARY),
+        ),
+    };
+    let content_type = contents.content_type().unwrap_or_default().to_string();
+    let message = RecordedMessage {
+        contents: MessageContents {
+            contents,
+            metadata: HashMap::from([("contentType".to_string(), json!(content_type))]),
+            ..Default::default()
+        },
+        metadata: HashMap::from([
+            (WEBSOCKET.to_string(), json!(url.to_string())),
+            (DIRECTION.to_string(), json!(frame.direction.name())),
+            (SEQUENCE.to_string(), json!(sequence)),
+            (
+                OFFSET_MILLIS.to_string(),
+                json!(frame.offset.as_millis() as u64),
+            ),
+        ]),
+    };
+    (description, message)
+}
+
+/// Whether the contents are of a binary frame, `ContentType::is_binary` does not count
+/// `application/octet-stream` as binary. An empty frame is read back without its content
+/// type, the metadata keeps it
+fn is_binary(contents: &MessageContents) -> bool {
+    let (content_type, hint) = match &contents.contents {
+        OptionalBody::Present(_, content_type, hint) => (content_type.clone(), *hint),
+        _ => {
+            let content_type = contents.metadata.get("contentType").and_then(Value::as_str);
+            (
+                content_type.and_then(|value| ContentType::parse(value).ok()),
+                None,
+            )
+        }
+    };
+    hint == Some(ContentTypeHint::BINARY)
+        || content_type.is_some_and(|content_type| {
+            content_type.is_binary()
+                || (content_type.main_type == "application"
+                    && content_type.sub_type == "octet-stream")
+        })
+}
+
+/// Frames recorded for the url, in the order of the connection
+fn recorded_frames(pacts: &Pacts, consumer: &str, provider: &str, url: &Url) -> Vec<Frame> {
+    let mut frames: Vec<(u64, Frame)> = pacts
+        .get_messages(consumer, provider)
+        .into_iter()
+        .filter(|message| message.metadata.get(WEBSOCKET) == Some(&json!(url.to_string())))
+        .filter_map(|message| {
+            let metadata = &message.metadata;
+            let direction = match metadata.get(DIRECTION)?.as_str()? {
+                "client" => Direction::Client,
+                _ => Direction::Server,
+            };
+            let offset = Duration::from_millis(metadata.get(OFFSET_MILLIS)?.as_u64()?);
+            let sequence = metadata.get(SEQUENCE)?.as_u64()?;
+            let data = message
+                .contents
+                .contents
+                .value()
+                .unwrap_or_default()
+                .to_vec();
+            let message = match is_binary(&message.contents) {
+                true => Message::Binary(data),
+                false => Message::Text(String::from_utf8_lossy(&data).to_string()),
+            };
+            Some((
+                sequence,
+                Frame {
+                    direction,
+                    offset,
+                    message,
+                },
+            ))
+        })
+        .collect();
+    frames.sort_by_key(|(sequence, _)| *sequence);
+    frames.into_iter().map(|(_, frame)| frame).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::PactServer;
+    use crate::test_utils::{record_then_replay, test_pacts_folder, TestUpstream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    #[test]
+    fn test_same_message() {
+        assert!(same_message(
+            &Message::Text(r#"{"op": "subscribe", "args": ["trades"]}"#.to_string()),
+            &Message::Text(r#"{"args":["trades"],"op":"subscribe"}"#.to_string()),
+        ));
+        assert!(!same_message(
+            &Message::Text("ping".to_string()),
+            &Message::Binary(b"ping".to_vec()),
+        ));
+        assert_eq!(
+            websocket_url("/https/stream.binance.com/ws?streams=btcusdt")
+                .unwrap()
+                .as_str(),
+            "wss://stream.binance.com/ws?streams=btcusdt"
+        );
+    }
+
+    /// Upstream greeting every connection, answering `subscribe <topic>` with two updates
+    async fn start_upstream(connections: Arc<AtomicUsize>) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut websocket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    websocket
+                        .send(Message::Text("welcome".to_string()))
+                        .await
+                        .unwrap();
+                    while let Some(Ok(message)) = websocket.next().await {
+                        let text = match message {
+                            Message::Text(text) => text,
+                            // binary frames are echoed back reversed
+                            Message::Binary(mut data) => {
+                                data.reverse();
+                                let _ = websocket.send(Message::Binary(data)).await;
+                                continue;
+                            }
+                            _ => break,
+                        };
+                        let topic = text.trim_start_matches("subscribe ").to_string();
+                        for update in [1, 2] {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            let _ = websocket
+                                .send(Message::Text(format!("{topic} {update}")))
+                                .await;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_websocket() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let upstream_port = start_upstream(connections.clone()).await;
+        let pacts_folder = test_pacts_folder();
+        record_then_replay(
+            &pacts_folder,
+            |builder| builder,
+            |port| async move {
+                let url = format!("ws://localhost:{port}/http/127.0.0.1:{upstream_port}/stream");
+                let (mut websocket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+                let mut received = vec![websocket.next().await.unwrap().unwrap()];
+                websocket
+                    .send(Message::Text("subscribe trades".to_string()))
+                    .await
+                    .unwrap();
+                let started = Instant::now();
+                for _ in 0..2 {
+                    received.push(websocket.next().await.unwrap().unwrap());
+                }
+                assert!(started.elapsed() >= Duration::from_millis(90));
+                assert_eq!(
+                    received,
+                    ["welcome", "trades 1", "trades 2"].map(|text| Message::Text(text.to_string()))
+                );
+                websocket.close(None).await.unwrap();
+                while websocket.next().await.is_some() {}
+            },
+        )
+        .await;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        let pact = std::fs::read_to_string(pacts_folder.join("consumer-127.0.0.1.json")).unwrap();
+        assert!(pact.contains("Asynchronous/Messages"));
+        assert!(pact.contains(r#""direction": "server""#));
+        assert!(pact.contains("trades 2"));
+        assert!(pact.contains(r#""name": "pact-proxy-rs""#));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_websocket_binary_frames() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let upstream_port = start_upstream(connections.clone()).await;
+        let pacts_folder = test_pacts_folder();
+        record_then_replay(
+            &pacts_folder,
+            |builder| builder,
+            |port| async move {
+                let url = format!("ws://localhost:{port}/http/127.0.0.1:{upstream_port}/binary");
+                let (mut websocket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+                let welcome = websocket.next().await.unwrap().unwrap();
+                assert_eq!(welcome, Message::Text("welcome".to_string()));
+                // not utf-8, utf-8 that stays binary, and empty
+                for data in [&b"\x00\xff\xfe not utf-8 \xc3\x28"[..], b"ping", b""] {
+                    websocket
+                        .send(Message::Binary(data.to_vec()))
+                        .await
+                        .unwrap();
+                    let mut echoed = data.to_vec();
+                    echoed.reverse();
+                    let received = websocket.next().await.unwrap().unwrap();
+                    assert_eq!(received, Message::Binary(echoed));
+                }
+                websocket.close(None).await.unwrap();
+                while websocket.next().await.is_some() {}
+            },
+        )
+        .await;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    /// Reads a response with a content length from the connection, returns its body
+    async fn read_response<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> String {
+        let mut content_length = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = Some(value.trim().parse().unwrap());
+                }
+            }
+        }
+        let mut body = vec![0; content_length.expect("response should have a content length")];
+        reader.read_exact(&mut body).await.unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_upgrade_on_keep_alive_connection() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let websocket_port = start_upstream(connections.clone()).await;
+        let upstream = TestUpstream::start(|request| {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            tiny_http::Response::from_string(format!("{} {body}", request.method())).boxed()
+        });
+        let pacts_folder = test_pacts_folder();
+        let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+        let port = pact_server.port().unwrap();
+        let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+        let stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        let path = format!("/http/127.0.0.1:{}", upstream.port);
+        stream
+            .write_all(format!("GET {path}/get HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut stream).await, "GET ");
+        // the chunked body is passed through to the http server as it was sent
+        stream
+            .write_all(
+                format!(
+                    "POST {path}/post HTTP/1.1\r\nHost: localhost\r\n\
+                     Transfer-Encoding: chunked\r\n\r\n\
+                     5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut stream).await, "POST hello world");
+        let url = format!("ws://localhost:{port}/http/127.0.0.1:{websocket_port}/stream");
+        let (mut websocket, _) = tokio_tungstenite::client_async(url, stream.into_inner())
+            .await
+            .unwrap();
+        assert_eq!(
+            websocket.next().await.unwrap().unwrap(),
+            Message::Text("welcome".to_string())
+        );
+        websocket.close(None).await.unwrap();
+        while websocket.next().await.is_some() {}
+        pact_server.stop().unwrap();
+        assert_eq!(upstream.hits(), 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_integration_websocket_sessions() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let upstream_port = start_upstream(connections.clone()).await;
+        let pacts_folder = test_pacts_folder();
+        let path = format!("/http/127.0.0.1:{upstream_port}/stream");
+        // recorded in the session of the path prefix, replayed in the one of the header
+        for session_header in [None, Some("funded")] {
+            let mut pact_server = PactServer::with_http_server(&pacts_folder, None, None).unwrap();
+            let port = pact_server.port().unwrap();
+            let mut pact_server = pact_server.start_non_blocking().await.unwrap();
+            let mut request = match session_header {
+                Some(_) => format!("ws://localhost:{port}{path}"),
+                None => format!("ws://localhost:{port}/_session/funded{path}"),
+            }
+            .into_client_request()
+            .unwrap();
+            if let Some(session) = session_header {
+                request
+                    .headers_mut()
+                    .insert("X-Pact-Session", HeaderValue::from_static(session));
+            }
+            let (mut websocket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            assert_eq!(
+                websocket.next().await.unwrap().unwrap(),
+                Message::Text("welcome".to_string())
+            );
+            websocket.close(None).await.unwrap();
+            while websocket.next().await.is_some() {}
+            pact_server.stop().unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        let pact =
+            std::fs::read_to_string(pacts_folder.join("consumer@funded-127.0.0.1.json")).unwrap();
+        assert!(pact.contains("welcome"));
+        assert!(!pacts_folder.join("consumer-127.0.0.1.json").exists());
+    }
+}
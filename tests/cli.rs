@@ -0,0 +1,78 @@
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_readiness_line_and_port_file() {
+    let folder = std::env::temp_dir().join(format!("pact-proxy-rs-{}", rand::random::<u64>()));
+    let port_file = folder.join("port");
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_pact-proxy-rs"))
+        .arg("--pact_files_folder")
+        .arg(folder.join("pacts"))
+        .arg("--port_file")
+        .arg(&port_file)
+        .arg("--tls_self_signed")
+        .arg(folder.join("tls"))
+        .args(["--route", "0=https://api.example.com"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(proxy.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+
+    // the first line on stdout is the readiness json
+    let readiness: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(readiness["ready"], true);
+    let port = readiness["port"].as_u64().unwrap();
+    assert_ne!(port, 0);
+    assert_eq!(
+        std::fs::read_to_string(&port_file).unwrap(),
+        port.to_string()
+    );
+    let route = &readiness["routes"][0];
+    assert_eq!(route["upstream"], "https://api.example.com/");
+    assert_ne!(route["port"].as_u64().unwrap(), 0);
+    let tls_certificate = readiness["tlsCertificate"].as_str().unwrap();
+    assert!(std::path::Path::new(tls_certificate).exists());
+    let mut rest = String::new();
+    std::io::Read::read_to_string(&mut stdout, &mut rest).unwrap();
+    assert_eq!(rest, "");
+    std::fs::remove_dir_all(folder).unwrap();
+}
+
+#[test]
+fn test_invalid_options_are_rejected() {
+    let folder = std::env::temp_dir().join(format!("pact-proxy-rs-{}", rand::random::<u64>()));
+    for options in [
+        &[
+            "--grpc_upstream",
+            "http://localhost:50051",
+            "--bind",
+            "[::]:0",
+        ][..],
+        &[
+            "--grpc_upstream",
+            "http://localhost:50051",
+            "--tls_self_signed",
+            "tls",
+        ],
+        &["--port", "0", "--bind", "127.0.0.1:0"],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_pact-proxy-rs"))
+            .arg("--pact_files_folder")
+            .arg(folder.join("pacts"))
+            .args(options)
+            .current_dir(std::env::temp_dir())
+            .output()
+            .unwrap();
+        // no readiness line, the builder's error instead
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "", "{options:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Error: "), "{options:?}: {stderr}");
+    }
+    let _ = std::fs::remove_dir_all(folder);
+}